@@ -1,37 +1,56 @@
 use clap::*;
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{read_to_string, File};
 use std::io::prelude::*;
+use std::io::{stdin, stdout};
 use std::path::Path;
+use std::process;
+use stevia::diagnostic::Diagnostic;
 use stevia::epub_writer::EpubWriter;
 use stevia::reader::Reader;
+use stevia::runtime::StoryPlayer;
+use stevia::story::Command;
 use stevia::writer::Writer;
 
 // Launch exporter with
 // ./target/debug/stevia ./examples/example.ink epub
+// Play a story in the terminal with
+// ./target/debug/stevia play ./examples/example.ink
 
 fn main() {
     let matches = App::new("stevia")
         .version("0.1")
         .author("Giorgio Pomettini <giorgio.pomettini@gmail.com>")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("file").index(1).required(true))
         .arg(Arg::with_name("export-format").index(2))
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("Plays an .ink or .stevia file in the terminal")
+                .arg(Arg::with_name("file").index(1).required(true)),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("play") {
+        let path = Path::new(matches.value_of("file").expect("Missing file argument"));
+        play(path);
+        return;
+    }
+
     let path = Path::new(matches.value_of("file").expect("Missing file argument"));
     let export_format = matches.value_of("export-format");
 
-    let mut file = File::open(path).expect("File not found");
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Cannot read the file");
-
-    let mut reader = Reader::from_text(&contents);
-    reader.parse_all_lines();
+    let mut reader = Reader::from_file(path).expect("File not found");
+    if let Err(diagnostics) = reader.parse_all_lines() {
+        exit_with_diagnostics(&reader, &diagnostics);
+    }
 
     match export_format {
         None | Some("stevia") => {
             let mut writer = Writer::new();
-            writer.process_lines(&reader);
+            if let Err(diagnostics) = writer.process_lines(&reader) {
+                exit_with_diagnostics(&reader, &diagnostics);
+            }
 
             // FIXME: Needs refactor
             let mut output_file = File::create(format!(
@@ -50,7 +69,9 @@ fn main() {
             // TODO: Remove hardcoded values
             let mut epub_writer =
                 EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
-            epub_writer.process_lines(&reader);
+            if let Err(diagnostics) = epub_writer.process_lines(&reader) {
+                exit_with_diagnostics(&reader, &diagnostics);
+            }
 
             let epub = epub_writer.generate();
             if let Some(contents) = epub {
@@ -62,6 +83,147 @@ fn main() {
     }
 }
 
+fn play(path: &Path) {
+    // Knots can be reached by name only when they come from the .ink file
+    let (output, symbols) = if path.extension() == Some("stevia".as_ref()) {
+        let output = read_to_string(path).expect("File not found");
+        (output, HashMap::new())
+    } else {
+        let mut reader = Reader::from_file(path).expect("File not found");
+        if let Err(diagnostics) = reader.parse_all_lines() {
+            exit_with_diagnostics(&reader, &diagnostics);
+        }
+
+        let mut writer = Writer::new();
+        if let Err(diagnostics) = writer.process_lines(&reader) {
+            exit_with_diagnostics(&reader, &diagnostics);
+        }
+
+        (writer.output, writer.symbols)
+    };
+
+    let start = StoryPlayer::load(&output).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path.display(), error);
+        process::exit(1);
+    });
+
+    let mut player = start.clone();
+    // States before each choice, to be restored by undo
+    let mut history = Vec::new();
+    let mut input = String::new();
+
+    loop {
+        loop {
+            match player.continue_line() {
+                Ok(paragraph) => {
+                    for command in player.take_commands() {
+                        println!("[{}]", command_text(&command));
+                    }
+
+                    match paragraph {
+                        Some(paragraph) => {
+                            match paragraph.speaker {
+                                Some(speaker) => println!("{}: {}", speaker, paragraph.text),
+                                None => println!("{}", paragraph.text),
+                            }
+
+                            if !paragraph.tags.is_empty() {
+                                println!("# {}", paragraph.tags.join(", "));
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                Err(error) => {
+                    eprintln!("{}: {}", path.display(), error);
+                    process::exit(1);
+                }
+            }
+        }
+
+        for (index, choice) in player.current_choices().iter().enumerate() {
+            println!("{}: {}", index + 1, choice.label);
+        }
+
+        if player.is_done() {
+            println!("--- Done ---");
+        } else if player.is_ended() {
+            println!("--- The End ---");
+        }
+
+        print!("> ");
+        stdout().flush().expect("Cannot write to the terminal");
+
+        input.clear();
+        if stdin().read_line(&mut input).expect("Cannot read input") == 0 {
+            println!();
+            break;
+        }
+
+        let mut words = input.split_whitespace();
+
+        match (words.next(), words.next()) {
+            (Some("quit"), None) => break,
+            (Some("undo"), None) => match history.pop() {
+                Some(previous) => player = previous,
+                None => println!("Nothing to undo"),
+            },
+            (Some("restart"), None) => {
+                history.clear();
+                player = start.clone();
+            }
+            (Some("goto"), Some(name)) => {
+                // Jump places can be used when the knot names are not known
+                match symbols.get(name).copied().or_else(|| name.parse().ok()) {
+                    Some(target) => {
+                        history.push(player.clone());
+                        if let Err(error) = player.go_to(target) {
+                            history.pop();
+                            println!("{}", error);
+                        }
+                    }
+                    None => println!("Knot {} not found", name),
+                }
+            }
+            (Some(number), None) if matches!(number.parse::<usize>(), Ok(n) if n > 0) => {
+                let index = number.parse::<usize>().unwrap_or_default() - 1;
+
+                history.push(player.clone());
+                if let Err(error) = player.choose(index) {
+                    history.pop();
+                    println!("{}", error);
+                }
+            }
+            _ => println!("Type the number of a choice, undo, restart, goto <knot> or quit"),
+        }
+    }
+}
+
+fn command_text(command: &Command) -> String {
+    match command {
+        Command::Background(asset) => format!("background {}", asset),
+        Command::Show {
+            asset, position, ..
+        } => match position {
+            Some(position) => format!("show {} {}", asset, position),
+            None => format!("show {}", asset),
+        },
+        Command::Hide(character) => format!("hide {}", character),
+        Command::Music(asset) => format!("music {}", asset),
+        Command::Sound(asset) => format!("sound {}", asset),
+    }
+}
+
+fn exit_with_diagnostics(reader: &Reader, diagnostics: &[Diagnostic]) -> ! {
+    for diagnostic in diagnostics {
+        // Errors can be in any of the included files
+        let path = &reader.files[diagnostic.file];
+        eprintln!("{}: {}", path.display(), diagnostic);
+    }
+
+    process::exit(1);
+}
+
 mod tests {
     #[allow(unused_imports)]
     use assert_cmd::prelude::*;
@@ -117,6 +279,91 @@ mod tests {
         clean();
     }
 
+    #[test]
+    fn test_functional_process_file_with_errors() {
+        write("broken.ink", "Hello {WORLD}\n+ [Yes -> like").unwrap();
+
+        Command::cargo_bin("stevia")
+            .unwrap()
+            .arg("broken.ink")
+            .assert()
+            .failure()
+            .stderr(
+                "broken.ink: error[E0004] at line 1, columns 7-13: Constant WORLD is not defined\n\
+                 broken.ink: error[E0002] at line 2, columns 1-14: Cannot get key of question\n",
+            );
+
+        remove_file("broken.ink").unwrap();
+
+        clean();
+    }
+
+    #[test]
+    fn test_functional_process_file_with_include() {
+        create_dir_all("include_test/chapters").unwrap();
+        write("include_test/main.ink", "INCLUDE chapters/one.ink\n-> one").unwrap();
+        write(
+            "include_test/chapters/one.ink",
+            "== one\nHello {WORLD}\nINCLUDE ../main.ink",
+        )
+        .unwrap();
+
+        Command::cargo_bin("stevia")
+            .unwrap()
+            .arg("include_test/main.ink")
+            .assert()
+            .failure()
+            .stderr(
+                "include_test/chapters/one.ink: error[E0004] at line 2, columns 7-13: Constant WORLD is not defined\n\
+                 include_test/chapters/one.ink: error[E0011] at line 3, columns 9-19: File ../main.ink includes itself\n",
+            );
+
+        remove_dir_all("include_test").unwrap();
+
+        clean();
+    }
+
+    #[test]
+    fn test_functional_play_file() {
+        Command::cargo_bin("stevia")
+            .unwrap()
+            .arg("play")
+            .arg("examples/example.ink")
+            .with_stdin()
+            .buffer("2\nundo\n1\ngoto hate\n")
+            .assert()
+            .success()
+            .stdout(
+                "Hello there\nI'm a VN written in the Ink format\nDo you like it?\n\
+                 1: Yes, I like it!\n2: No, I do not like it\n\
+                 > Oh, I see\n--- The End ---\n\
+                 > 1: Yes, I like it!\n2: No, I do not like it\n\
+                 > Thank you!\n--- The End ---\n\
+                 > Oh, I see\n--- The End ---\n> \n",
+            );
+
+        clean();
+    }
+
+    #[test]
+    fn test_functional_play_stevia_file() {
+        // Out of the working directory, so that clean() in other tests cannot delete it
+        let path = std::env::temp_dir().join("stevia_play_test.stevia");
+        write(&path, "P;Hello|Q;Yes;00020|P;Bye|E;").unwrap();
+
+        Command::cargo_bin("stevia")
+            .unwrap()
+            .arg("play")
+            .arg(&path)
+            .with_stdin()
+            .buffer("3\n1\n")
+            .assert()
+            .success()
+            .stdout("Hello\n1: Yes\n> There is no choice 3\n1: Yes\n> Bye\n--- The End ---\n> \n");
+
+        remove_file(&path).unwrap();
+    }
+
     #[allow(dead_code)]
     fn clean() {
         Command::new("find")
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UndefinedLine,
+    MalformedQuestion,
+    MalformedConstant,
+    UndefinedConstant,
+    UndefinedBookmark,
+    UnterminatedComment,
+    MalformedVariable,
+    MalformedExpression,
+    UndefinedVariable,
+    MissingInclude,
+    IncludeCycle,
+    MalformedCommand,
+    MissingAsset,
+    DuplicateBookmark,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // Index of the file in the reader, the first one is the main file
+    pub file: usize,
+    // Line of the source file, starting from 1
+    pub line: usize,
+    // Byte range of the offending text inside the line
+    pub span: Range<usize>,
+    pub message: String,
+    pub code: ErrorCode,
+}
+
+impl ErrorCode {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::UndefinedLine => "E0001",
+            Self::MalformedQuestion => "E0002",
+            Self::MalformedConstant => "E0003",
+            Self::UndefinedConstant => "E0004",
+            Self::UndefinedBookmark => "E0005",
+            Self::UnterminatedComment => "E0006",
+            Self::MalformedVariable => "E0007",
+            Self::MalformedExpression => "E0008",
+            Self::UndefinedVariable => "E0009",
+            Self::MissingInclude => "E0010",
+            Self::IncludeCycle => "E0011",
+            Self::MalformedCommand => "E0012",
+            Self::MissingAsset => "E0013",
+            Self::DuplicateBookmark => "E0014",
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, line: usize, span: Range<usize>, message: &str) -> Self {
+        Self {
+            severity: Severity::Error,
+            file: 0,
+            line,
+            span,
+            message: message.to_string(),
+            code,
+        }
+    }
+
+    pub fn warning(code: ErrorCode, line: usize, span: Range<usize>, message: &str) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(code, line, span, message)
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Columns are shown starting from 1, like most editors do
+        write!(
+            f,
+            "{}[{}] at line {}, columns {}-{}: {}",
+            self.severity,
+            self.code.as_str(),
+            self.line,
+            self.span.start + 1,
+            self.span.end,
+            self.message
+        )
+    }
+}
@@ -1,9 +1,11 @@
 use epub_builder::{EpubBuilder, EpubContent, ReferenceType, ZipLibrary};
 use image::*;
+use crate::diagnostic::*;
 use crate::reader::*;
-use regex::Regex;
+use crate::story::*;
 use std::collections::*;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 pub struct EpubWriter<'a> {
     pub title: String,
@@ -12,6 +14,20 @@ pub struct EpubWriter<'a> {
     pub page_content: Vec<String>,
     pub bookmark_table: HashMap<String, usize>,
     pub constants: HashMap<String, String>,
+    // Pages reached only after taking a choice, added after the chapters
+    pub choice_pages: Vec<String>,
+    // Pages reached at the end of the story and at the end of a flow, if there are any
+    pub end_page: Option<usize>,
+    pub done_page: Option<usize>,
+    // Pages opened by the text of a choice and followed by the page of its target,
+    // filled once every chapter has been written
+    pub output_pages: Vec<(usize, String, usize)>,
+    // Images shown inside the pages, next to the cover
+    pub backgrounds: Vec<String>,
+    // Where background images are, the folder of the story if not set
+    pub asset_directory: Option<PathBuf>,
+    // Background images converted to JPEG, ready to be added to the book
+    pub background_images: Vec<(String, Vec<u8>)>,
 }
 
 impl<'a> EpubWriter<'a> {
@@ -23,86 +39,301 @@ impl<'a> EpubWriter<'a> {
             page_content: Vec::new(),
             bookmark_table: HashMap::new(),
             constants: HashMap::new(),
+            choice_pages: Vec::new(),
+            end_page: None,
+            done_page: None,
+            output_pages: Vec::new(),
+            backgrounds: Vec::new(),
+            asset_directory: None,
+            background_images: Vec::new(),
         }
     }
 
     pub fn process_bookmark_table(&mut self, input: &Reader) {
-        let mut current_page: usize = 0;
+        // Every knot and stitch has its own page, the first one is for the introduction
+        for (current_page, bookmark) in input.bookmarks().into_iter().enumerate() {
+            self.bookmark_table.insert(bookmark, current_page + 1);
+        }
+    }
+
+    pub fn process_lines(&mut self, input: &Reader) -> Result<(), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let story = &input.story;
+
+        self.process_bookmark_table(input);
+        self.constants.clone_from(&story.constants);
+
+        // The first page is the introduction
+        let content = self.process_nodes(story, &story.nodes, None, &mut diagnostics);
+        self.page_content.push(content);
+
+        for knot in &story.knots {
+            let content = self.process_nodes(story, &knot.nodes, None, &mut diagnostics);
+            self.page_content.push(content);
+
+            for stitch in &knot.stitches {
+                let content = self.process_nodes(story, &stitch.nodes, None, &mut diagnostics);
+                self.page_content.push(content);
+            }
+        }
 
-        for line in &input.lines {
-            if line.type_ == LineType::Bookmark {
-                current_page += 1;
+        for (page, output, target) in std::mem::take(&mut self.output_pages) {
+            let content = format!("{}{}", output, self.page_content[target]);
+            self.fill_page(page, content);
+        }
+
+        self.page_content.append(&mut self.choice_pages);
+
+        self.process_backgrounds(input, &mut diagnostics);
+
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    // Images are read here, so that missing ones are reported along with the other errors
+    fn process_backgrounds(&mut self, input: &Reader, diagnostics: &mut Vec<Diagnostic>) {
+        let directory = self.asset_directory.clone().unwrap_or_else(|| {
+            input.files[0]
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        });
 
-                // Remove equal characters and white spaces
-                let chars_to_trim: &[char] = &['=', ' '];
+        for background in self.backgrounds.clone() {
+            // They can be JPEG or PNG files
+            let path = ["jpg", "jpeg", "png"]
+                .iter()
+                .map(|extension| directory.join(format!("{}.{}", background, extension)))
+                .find(|path| path.exists());
 
-                // Add the new string to the symbols
-                let trimmed_string: &str = line.text.trim_matches(chars_to_trim);
+            let jpg = match &path {
+                Some(path) => image::open(path)
+                    .and_then(|image| {
+                        let mut jpg = Vec::new();
+                        image.write_to(&mut jpg, ImageFormat::Jpeg).map(|_| jpg)
+                    })
+                    .map_err(|error| format!("Cannot read background {}: {}", background, error)),
+                None => Err(format!(
+                    "Cannot find background {}.jpg, .jpeg or .png",
+                    directory.join(&background).display()
+                )),
+            };
 
-                self.bookmark_table
-                    .insert(trimmed_string.to_string(), current_page);
+            match jpg {
+                Ok(jpg) => self.background_images.push((background, jpg)),
+                Err(message) => {
+                    for (line, range) in background_lines(input, &background) {
+                        diagnostics.push(line.error(ErrorCode::MissingAsset, range, &message));
+                    }
+                }
             }
         }
     }
 
-    pub fn process_lines(&mut self, input: &Reader) {
-        let mut current_page: usize = 0;
+    fn process_nodes(
+        &mut self,
+        story: &Story,
+        nodes: &[Node],
+        next: Option<&str>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> String {
+        // Pages of choices continue to the next page when their content is over
+        let mut content = String::new();
+        // Tags are added to the paragraph, choices, link or ending that follows them
+        let mut tags = Vec::new();
 
-        self.process_bookmark_table(input);
+        for (index, node) in nodes.iter().enumerate() {
+            match node {
+                Node::Paragraph(segments) => {
+                    content.push_str(&format!(
+                        "<p{}>{}</p>",
+                        tag_attributes(None, &tags),
+                        story.text(segments)
+                    ));
+                    tags.clear();
+                }
+                Node::Dialogue(character, segments) => {
+                    content.push_str(&format!(
+                        "<p{}><span class=\"speaker\">{}</span> {}</p>",
+                        tag_attributes(None, &tags),
+                        story.characters[*character],
+                        story.text(segments)
+                    ));
+                    tags.clear();
+                }
+                Node::Choices(choices) => {
+                    // Choices without a divert join back in a page with what follows the group
+                    let gather_page = if choices.iter().any(|choice| !choice.diverts()) {
+                        Some(self.reserve_page())
+                    } else {
+                        None
+                    };
+
+                    for choice in choices {
+                        let next = match (&choice.target, gather_page) {
+                            (Some(target), _) => self.chapter_page(target).map(Some),
+                            (None, Some(gather_page)) => Ok(Some(page_name(gather_page))),
+                            // The body diverts, so nothing comes after it
+                            (None, None) => Ok(None),
+                        };
+
+                        let body = choice.nodes();
+
+                        let page = if let [ending @ (Node::End | Node::Done)] = body.as_slice() {
+                            Ok(page_name(self.ending_page(ending)))
+                        } else if body.is_empty() {
+                            // Choices without a body and a target continue to the gather
+                            next.map(Option::unwrap)
+                        } else if let (Some(target), []) = (&choice.target, choice.body.as_slice())
+                        {
+                            // The text after the brackets is the first paragraph of the target page
+                            next.map(|_| {
+                                let page = self.reserve_page();
+                                let output = format!("<p>{}</p>", story.text(&choice.output));
+                                let target = self.bookmark_table[&target.name];
+                                self.output_pages.push((page, output, target));
+                                page_name(page)
+                            })
+                        } else {
+                            // The body has its own page, that continues to the next one
+                            let body_page = self.reserve_page();
+
+                            let body_content = match next {
+                                Ok(next) => {
+                                    self.process_nodes(story, &body, next.as_deref(), diagnostics)
+                                }
+                                Err(diagnostic) => {
+                                    diagnostics.push(diagnostic);
+                                    self.process_nodes(story, &body, None, diagnostics)
+                                }
+                            };
+
+                            self.fill_page(body_page, body_content);
+                            Ok(page_name(body_page))
+                        };
+
+                        // Books cannot hide choices, so the condition is left to the reader
+                        let condition = choice
+                            .condition
+                            .as_ref()
+                            .map_or_else(String::new, |condition| {
+                                format!(" (if {})", condition.text.replace('_', " "))
+                            });
+
+                        let choice_tags = [tags.as_slice(), &choice.tags].concat();
+
+                        match page {
+                            Ok(page) => content.push_str(&format!(
+                                "<p{}><a href=\"{}\">{}</a>{}</p>",
+                                tag_attributes(None, &choice_tags),
+                                page,
+                                story.text(&choice.label),
+                                condition
+                            )),
+                            Err(diagnostic) => diagnostics.push(diagnostic),
+                        }
+                    }
+
+                    tags.clear();
 
-        // Put an empty string to the first index of the vector
-        self.page_content.push(String::new());
+                    if let Some(gather_page) = gather_page {
+                        let gather_content =
+                            self.process_nodes(story, &nodes[index + 1..], next, diagnostics);
+                        self.fill_page(gather_page, gather_content);
 
-        for (current_line, line) in input.lines.iter().enumerate() {
-            match line.type_ {
-                LineType::Undefined => panic!(format!("Line {} cannot be parsed", &current_line)),
-                LineType::Text => {
-                    self.page_content[current_page].push_str(&format!("<p>{}</p>", &line.text));
+                        return content;
+                    }
                 }
-                // TODO: Implement questions
-                LineType::Question => {
-                    // Check between brackets
-                    let re_text = Regex::new(r"\[(.*?)\]")
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get key of question while parsing at line {}",
-                                &current_line
-                            )
-                        });
-
-                    // Check after arrow
-                    let re_jump = Regex::new(r"\->\s+(.*)$")
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get value of question while parsing at line {}",
-                                &current_line
-                            )
-                        });
-
-                    let title = re_text[1].to_string();
-                    let page = format!(
-                        "chapter_{}.xhtml",
-                        self.bookmark_table[&re_jump[1].to_string()]
-                    );
-
-                    self.page_content[current_page]
-                        .push_str(&format!("<p><a href=\"{}\">{}</a></p>", page, title));
+                Node::Command(Command::Background(background)) => {
+                    content.push_str(&format!(
+                        "<img class=\"background\" src=\"{}.jpg\" alt=\"{}\"/>",
+                        background, background
+                    ));
+
+                    if !self.backgrounds.contains(background) {
+                        self.backgrounds.push(background.clone());
+                    }
+                }
+                // Books cannot show sprites or play sounds
+                Node::Command(_) => {}
+                Node::Jump(target) => {
+                    match self.chapter_page(target) {
+                        Ok(page) => content.push_str(&continue_link(&page, &tags)),
+                        Err(diagnostic) => diagnostics.push(diagnostic),
+                    }
+
+                    tags.clear();
                 }
-                LineType::Bookmark => {
-                    self.page_content.push(String::new());
-                    current_page += 1;
+                Node::End | Node::Done => {
+                    content.push_str(&ending_content(node, &tags));
+                    tags.clear();
                 }
-                // TODO: Implement constants
-                LineType::Constant => {}
-                // TODO: Implement end
-                LineType::End => {}
-                _ => (),
+                // Books have no state, so variables always show their initial value
+                // Glue is left only between pages, that cannot be joined
+                Node::Assignment(_, _) | Node::Glue => {}
+                Node::Tags(node_tags) => tags.extend(node_tags.iter().cloned()),
+            }
+        }
+
+        if let Some(next) = next {
+            if !matches!(nodes.last(), Some(node) if node.diverts()) {
+                content.push_str(&continue_link(next, &tags));
             }
         }
+
+        content
+    }
+
+    // Choice pages come after the introduction and the chapters
+    fn reserve_page(&mut self) -> usize {
+        self.choice_pages.push(String::new());
+        self.bookmark_table.len() + self.choice_pages.len()
+    }
+
+    // Choices that only end the story share the same page, one for END and one for DONE
+    fn ending_page(&mut self, ending: &Node) -> usize {
+        let page = match ending {
+            Node::Done => self.done_page,
+            _ => self.end_page,
+        };
+
+        if let Some(page) = page {
+            return page;
+        }
+
+        let page = self.reserve_page();
+        self.fill_page(page, ending_content(ending, &[]));
+
+        match ending {
+            Node::Done => self.done_page = Some(page),
+            _ => self.end_page = Some(page),
+        }
+
+        page
+    }
+
+    fn fill_page(&mut self, page: usize, content: String) {
+        let index = page - self.bookmark_table.len() - 1;
+        self.choice_pages[index] = content;
+    }
+
+    fn chapter_page(&self, target: &Target) -> Result<String, Diagnostic> {
+        if let Some(page) = self.bookmark_table.get(&target.name) {
+            Ok(page_name(*page))
+        } else {
+            Err(Diagnostic {
+                file: target.file,
+                ..Diagnostic::error(
+                    ErrorCode::UndefinedBookmark,
+                    target.line,
+                    target.span.clone(),
+                    &format!("Bookmark {} is not defined", target.name),
+                )
+            })
+        }
     }
 
     // TODO: Handle errors
@@ -112,7 +343,7 @@ impl<'a> EpubWriter<'a> {
         let mut jpg = Vec::new();
         image.write_to(&mut jpg, ImageFormat::Jpeg).unwrap();
 
-        let css = "";
+        let css = ".speaker { font-weight: bold; } .background { width: 100%; }";
 
         let mut epub: Vec<u8> = Vec::new();
 
@@ -134,6 +365,13 @@ impl<'a> EpubWriter<'a> {
             .add_cover_image("cover.jpg", jpg.as_slice(), "image/jpeg")
             .unwrap();
 
+        // Add backgrounds
+        for (background, jpg) in &self.background_images {
+            builder
+                .add_resource(format!("{}.jpg", background), jpg.as_slice(), "image/jpeg")
+                .ok()?;
+        }
+
         // Add cover file
         builder
             .add_content(
@@ -226,3 +464,82 @@ impl<'a> EpubWriter<'a> {
 </html>"#, content)
     }
 }
+
+// Lines that show a background, with the position of its name
+fn background_lines<'a>(
+    input: &'a Reader,
+    background: &str,
+) -> impl Iterator<Item = (&'a Line, Range<usize>)> {
+    let background = background.to_string();
+
+    input
+        .lines
+        .iter()
+        .filter(|line| line.type_ == LineType::Command)
+        .filter_map(move |line| {
+            let words: Vec<&str> = line.text[1..].split_whitespace().collect();
+            let start = line.text.rfind(background.as_str())?;
+
+            if words == ["bg", background.as_str()] {
+                Some((line, start..start + background.len()))
+            } else {
+                None
+            }
+        })
+}
+
+fn page_name(page: usize) -> String {
+    format!("chapter_{}.xhtml", page)
+}
+
+fn continue_link(page: &str, tags: &[String]) -> String {
+    format!(
+        "<p{}><a href=\"{}\">Continue</a></p>",
+        tag_attributes(None, tags),
+        page
+    )
+}
+
+// END closes the story, while DONE closes only the current part of it
+fn ending_content(ending: &Node, tags: &[String]) -> String {
+    let (class, text) = match ending {
+        Node::Done => ("done", "To be continued"),
+        _ => ("end", "The End"),
+    };
+
+    format!("<p{}>{}</p>", tag_attributes(Some(class), tags), text)
+}
+
+// Every tag becomes a class after the element's own, and tags like "key: value" also
+// become data attributes
+fn tag_attributes(class: Option<&str>, tags: &[String]) -> String {
+    if class.is_none() && tags.is_empty() {
+        return String::new();
+    }
+
+    let mut classes: Vec<String> = class.map(String::from).into_iter().collect();
+    let mut data = String::new();
+
+    for tag in tags {
+        classes.push(format!("tag-{}", css_name(tag)));
+
+        if let Some((key, value)) = tag.split_once(':') {
+            data.push_str(&format!(
+                " data-{}=\"{}\"",
+                css_name(key),
+                value.trim().replace('"', "&quot;")
+            ));
+        }
+    }
+
+    format!(" class=\"{}\"{}", classes.join(" "), data)
+}
+
+// Lowercase words separated by dashes
+fn css_name(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
@@ -0,0 +1,381 @@
+use regex::Regex;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Number(i32),
+    Text(String),
+    Boolean(bool),
+    Variable(String),
+    // How many times a knot or a stitch has been visited
+    Visits(String),
+    Operator(&'static str),
+}
+
+// Expressions are kept in reverse polish notation, so that the runtime
+// can evaluate them with a stack and without parenthesis
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    pub tokens: Vec<Token>,
+}
+
+// Binary operators with their precedence, the higher binds tighter
+const BINARY_OPERATORS: &[(&str, &str, u8)] = &[
+    ("||", "or", 1),
+    ("or", "or", 1),
+    ("&&", "and", 2),
+    ("and", "and", 2),
+    ("==", "==", 3),
+    ("!=", "!=", 3),
+    ("<", "<", 4),
+    (">", ">", 4),
+    ("<=", "<=", 4),
+    (">=", ">=", 4),
+    ("+", "+", 5),
+    ("-", "-", 5),
+    ("*", "*", 6),
+    ("/", "/", 6),
+    ("%", "%", 6),
+    ("mod", "%", 6),
+];
+
+const UNARY_PRECEDENCE: u8 = 7;
+
+enum Pending {
+    Operator(&'static str, u8),
+    Parenthesis,
+}
+
+impl Token {
+    // Values that can be written as they are, like the initial value of a variable
+    pub fn literal(text: &str) -> Option<Self> {
+        let text = text.trim();
+
+        if let Ok(number) = text.parse::<i32>() {
+            Some(Self::Number(number))
+        } else if text == "true" || text == "false" {
+            Some(Self::Boolean(text == "true"))
+        } else if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+            Some(Self::Text(unquote(&text[1..text.len() - 1])))
+        } else {
+            None
+        }
+    }
+
+    // Strings are printed without their quotes
+    pub fn value_text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            _ => self.to_string(),
+        }
+    }
+
+    // Zero, empty strings and false are false, like in Ink
+    pub fn is_true(&self) -> bool {
+        match self {
+            Self::Number(number) => *number != 0,
+            Self::Text(text) => !text.is_empty(),
+            Self::Boolean(boolean) => *boolean,
+            Self::Variable(_) | Self::Visits(_) | Self::Operator(_) => false,
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let re_token = Regex::new(
+            r#"^\s*(?:(?P<number>\d+)|(?P<text>"(?:[^"\\]|\\.)*")|(?P<name>[A-Za-z_][\w.]*)|(?P<operator>==|!=|<=|>=|&&|\|\||[-+*/%<>!()]))"#,
+        )
+        .unwrap();
+
+        let mut tokens = Vec::new();
+        let mut pending = Vec::new();
+        let mut position = 0;
+        // An operand is expected at the start, after an operator and after a parenthesis
+        let mut expect_operand = true;
+
+        while !text[position..].trim().is_empty() {
+            let caps = if let Some(caps) = re_token.captures(&text[position..]) {
+                caps
+            } else {
+                return Err(format!(
+                    "Unexpected character {}",
+                    text[position..].trim_start().chars().next().unwrap()
+                ));
+            };
+
+            position += caps.get(0).unwrap().end();
+
+            let word = caps
+                .name("name")
+                .or_else(|| caps.name("operator"))
+                .map(|word| word.as_str());
+
+            if let Some(number) = caps.name("number") {
+                match number.as_str().parse() {
+                    Ok(number) => tokens.push(Token::Number(number)),
+                    Err(_) => return Err(format!("Number {} is too big", number.as_str())),
+                }
+                expect_operand = false;
+            } else if let Some(literal) = caps.name("text") {
+                tokens.push(Token::literal(literal.as_str()).unwrap());
+                expect_operand = false;
+            } else if word == Some("(") {
+                pending.push(Pending::Parenthesis);
+                expect_operand = true;
+            } else if word == Some(")") {
+                loop {
+                    match pending.pop() {
+                        Some(Pending::Operator(operator, _)) => {
+                            tokens.push(Token::Operator(operator));
+                        }
+                        Some(Pending::Parenthesis) => break,
+                        None => return Err(String::from("Unexpected closing parenthesis")),
+                    }
+                }
+                expect_operand = false;
+            } else if expect_operand
+                && (word == Some("-") || word == Some("!") || word == Some("not"))
+            {
+                // Unary operators are applied after their operand
+                let operator = if word == Some("-") { "neg" } else { "not" };
+                pending.push(Pending::Operator(operator, UNARY_PRECEDENCE));
+            } else if let Some((_, operator, precedence)) = BINARY_OPERATORS
+                .iter()
+                .find(|(symbol, _, _)| Some(*symbol) == word)
+            {
+                // Operators with the same precedence are evaluated from left to right
+                while let Some(Pending::Operator(previous, previous_precedence)) = pending.last() {
+                    if *previous_precedence < *precedence {
+                        break;
+                    }
+                    tokens.push(Token::Operator(previous));
+                    pending.pop();
+                }
+
+                pending.push(Pending::Operator(operator, *precedence));
+                expect_operand = true;
+            } else if let Some(literal) = word.and_then(Token::literal) {
+                tokens.push(literal);
+                expect_operand = false;
+            } else if let Some(name) = caps.name("name") {
+                tokens.push(Token::Variable(name.as_str().to_string()));
+                expect_operand = false;
+            } else {
+                return Err(format!("Unexpected operator {}", word.unwrap_or_default()));
+            }
+        }
+
+        while let Some(pending) = pending.pop() {
+            match pending {
+                Pending::Operator(operator, _) => tokens.push(Token::Operator(operator)),
+                Pending::Parenthesis => return Err(String::from("Parenthesis is never closed")),
+            }
+        }
+
+        let expression = Self { tokens };
+
+        if expression.is_valid() {
+            Ok(expression)
+        } else {
+            Err(String::from("Expression is not complete"))
+        }
+    }
+
+    // Reads back an expression as it's written in the .stevia file
+    pub fn from_rpn(text: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        let mut rest = text.trim_start();
+
+        while !rest.is_empty() {
+            // Strings can contain spaces, so they end at the closing quote
+            let end = if rest.starts_with('"') {
+                let mut escaped = false;
+                rest.char_indices()
+                    .skip(1)
+                    .find(|(_, char_)| {
+                        let quote = !escaped && *char_ == '"';
+                        escaped = !escaped && *char_ == '\\';
+                        quote
+                    })
+                    .map(|(position, _)| position + 1)
+                    .ok_or("String is never closed")?
+            } else {
+                rest.find(char::is_whitespace).unwrap_or(rest.len())
+            };
+
+            let word = &rest[..end];
+
+            let token = if let Some(literal) = Token::literal(word) {
+                literal
+            } else if let Some(place) = word.strip_prefix('@') {
+                Token::Visits(place.to_string())
+            } else if let Some(operator) = ["neg", "not"].iter().find(|op| **op == word) {
+                Token::Operator(operator)
+            } else if let Some((_, operator, _)) = BINARY_OPERATORS
+                .iter()
+                .find(|(_, operator, _)| *operator == word)
+            {
+                Token::Operator(operator)
+            } else if word.starts_with(|char_: char| char_.is_alphabetic() || char_ == '_') {
+                Token::Variable(word.to_string())
+            } else {
+                return Err(format!("Unexpected token {}", word));
+            };
+
+            tokens.push(token);
+            rest = rest[end..].trim_start();
+        }
+
+        let expression = Self { tokens };
+
+        if expression.is_valid() {
+            Ok(expression)
+        } else {
+            Err(String::from("Expression is not complete"))
+        }
+    }
+
+    // Names of the variables used by the expression
+    pub fn variables(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Variable(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The operand function gives the current value of variables and visit counts
+    pub fn evaluate<F>(&self, operand: F) -> Result<Token, String>
+    where
+        F: Fn(&Token) -> Option<Token>,
+    {
+        let mut stack = Vec::new();
+
+        for token in &self.tokens {
+            let value = match token {
+                Token::Operator(operator @ ("neg" | "not")) => {
+                    let value = stack.pop().ok_or("Expression is not complete")?;
+                    unary(operator, &value)?
+                }
+                Token::Operator(operator) => {
+                    let right = stack.pop().ok_or("Expression is not complete")?;
+                    let left = stack.pop().ok_or("Expression is not complete")?;
+                    binary(operator, &left, &right)?
+                }
+                Token::Variable(_) | Token::Visits(_) => {
+                    operand(token).ok_or_else(|| format!("{} has no value", token))?
+                }
+                _ => token.clone(),
+            };
+
+            stack.push(value);
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(value), true) => Ok(value),
+            _ => Err(String::from("Expression is not complete")),
+        }
+    }
+
+    // Every operator must have its operands, and a single value must be left
+    fn is_valid(&self) -> bool {
+        let mut depth: usize = 0;
+
+        for token in &self.tokens {
+            depth = match token {
+                Token::Operator("neg" | "not") if depth >= 1 => depth,
+                Token::Operator(_) if depth >= 2 => depth - 1,
+                Token::Operator(_) => return false,
+                _ => depth + 1,
+            };
+        }
+
+        depth == 1
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Text(text) => {
+                write!(f, "\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Self::Boolean(boolean) => write!(f, "{}", boolean),
+            Self::Variable(name) => write!(f, "{}", name),
+            Self::Visits(name) => write!(f, "@{}", name),
+            Self::Operator(operator) => write!(f, "{}", operator),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens: Vec<String> = self.tokens.iter().map(Token::to_string).collect();
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+fn unary(operator: &str, value: &Token) -> Result<Token, String> {
+    match (operator, value) {
+        ("neg", Token::Number(number)) => Ok(Token::Number(-number)),
+        ("not", value) => Ok(Token::Boolean(!value.is_true())),
+        _ => Err(format!("Cannot apply {} to {}", operator, value)),
+    }
+}
+
+fn binary(operator: &str, left: &Token, right: &Token) -> Result<Token, String> {
+    let overflow = || format!("Result of {} {} {} is too big", left, operator, right);
+
+    let value = match (operator, left, right) {
+        ("and", _, _) => Token::Boolean(left.is_true() && right.is_true()),
+        ("or", _, _) => Token::Boolean(left.is_true() || right.is_true()),
+        ("==", _, _) => Token::Boolean(left == right),
+        ("!=", _, _) => Token::Boolean(left != right),
+        ("/" | "%", Token::Number(_), Token::Number(0)) => {
+            return Err(String::from("Division by zero"))
+        }
+        (_, Token::Number(left), Token::Number(right)) => match operator {
+            "+" => Token::Number(left.checked_add(*right).ok_or_else(overflow)?),
+            "-" => Token::Number(left.checked_sub(*right).ok_or_else(overflow)?),
+            "*" => Token::Number(left.checked_mul(*right).ok_or_else(overflow)?),
+            "/" => Token::Number(left.checked_div(*right).ok_or_else(overflow)?),
+            "%" => Token::Number(left.checked_rem(*right).ok_or_else(overflow)?),
+            "<" => Token::Boolean(left < right),
+            ">" => Token::Boolean(left > right),
+            "<=" => Token::Boolean(left <= right),
+            ">=" => Token::Boolean(left >= right),
+            _ => return Err(format!("Unexpected operator {}", operator)),
+        },
+        // Adding to a string joins the two values
+        ("+", Token::Text(_), _) | ("+", _, Token::Text(_)) => {
+            Token::Text(format!("{}{}", left.value_text(), right.value_text()))
+        }
+        _ => {
+            return Err(format!(
+                "Cannot apply {} to {} and {}",
+                operator, left, right
+            ))
+        }
+    };
+
+    Ok(value)
+}
+
+fn unquote(text: &str) -> String {
+    let mut output = String::new();
+    let mut chars = text.chars();
+
+    while let Some(char_) = chars.next() {
+        if char_ == '\\' {
+            output.extend(chars.next());
+        } else {
+            output.push(char_);
+        }
+    }
+
+    output
+}
@@ -1,6 +1,7 @@
 use iui::prelude::*;
 use iui::controls::*;
 use std::path::{Path, PathBuf};
+use crate::diagnostic::*;
 use crate::reader::*;
 use crate::writer::*;
 use crate::epub_writer::*;
@@ -36,6 +37,21 @@ macro_rules! unwrap_or_return {
     };
 }
 
+macro_rules! evaluate_diagnostics_or_return {
+    ($condition:ident, $ctx:ident, $reader:ident, $success:expr, $fail:expr) => {
+        match $condition {
+            Ok(_) => {
+                log($ctx, $success);
+            }
+            Err(diagnostics) => {
+                log($ctx, $fail);
+                log_diagnostics($ctx, &$reader, &diagnostics);
+                return Err(());
+            }
+        };
+    };
+}
+
 #[derive(Clone)]
 pub struct LogContext<'a> {
     pub ui: &'a UI,
@@ -86,19 +102,20 @@ pub fn process(ctx: &mut LogContext, state: &State) -> Result<(), ()> {
         None => return Err(()),
     };
 
-    let file = File::open(input_file);
-    let mut file = unwrap_or_return!(file, ctx, "File loaded", "Cannot load the file");
-
-    let mut contents = String::new();
-    let file_contents = file.read_to_string(&mut contents);
-    evaluate_or_return!(file_contents, ctx, "File read", "Cannot read the file");
+    // Included files are found next to the file that includes them
+    let reader = Reader::from_file(input_file);
+    let mut reader = unwrap_or_return!(reader, ctx, "File loaded", "Cannot load the file");
 
     log(ctx, "Started parsing");
 
-    let mut reader = Reader::from_text(&contents);
-    reader.parse_all_lines();
-
-    log(ctx, "Completed parsing");
+    let parse_result = reader.parse_all_lines();
+    evaluate_diagnostics_or_return!(
+        parse_result,
+        ctx,
+        reader,
+        "Completed parsing",
+        "Cannot parse the Ink file"
+    );
 
     match state.export_format {
         None => return Err(()),
@@ -106,7 +123,14 @@ pub fn process(ctx: &mut LogContext, state: &State) -> Result<(), ()> {
             log(ctx, "Started exporting to Stevia");
 
             let mut writer = Writer::new();
-            writer.process_lines(&reader);
+            let writer_result = writer.process_lines(&reader);
+            evaluate_diagnostics_or_return!(
+                writer_result,
+                ctx,
+                reader,
+                "Processed Stevia lines",
+                "Cannot export to Stevia"
+            );
 
             // The output file path needs to have .stevia as file extension
             let file_create_result = File::create(output_file.with_extension("stevia"));
@@ -152,10 +176,16 @@ pub fn process(ctx: &mut LogContext, state: &State) -> Result<(), ()> {
             log(ctx, "Started parsing");
 
             let mut epub_writer = EpubWriter::new(&state.title, &state.author, cover_path);
-            epub_writer.process_lines(&reader);
+            let epub_writer_result = epub_writer.process_lines(&reader);
+            evaluate_diagnostics_or_return!(
+                epub_writer_result,
+                ctx,
+                reader,
+                "Processed ePub lines",
+                "Cannot export to ePub"
+            );
 
-            let epub_writer_result = epub_writer.generate();
-            let epub = if let Some(contents) = epub_writer_result {
+            let epub = if let Some(contents) = epub_writer.generate() {
                 log(ctx, "Completed parsing");
                 contents
             } else {
@@ -194,6 +224,14 @@ pub fn log(ctx: &mut LogContext, message: &str) {
     ctx.entry.set_value(ctx.ui, &content);
 }
 
+pub fn log_diagnostics(ctx: &mut LogContext, reader: &Reader, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        // Errors can be in any of the included files
+        let path = &reader.files[diagnostic.file];
+        log(ctx, &format!("{}: {}", path.display(), diagnostic));
+    }
+}
+
 pub fn clear_log(ctx: &mut LogContext) {
     ctx.entry.set_value(ctx.ui, "");
 }
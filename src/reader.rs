@@ -1,4 +1,10 @@
+use crate::diagnostic::*;
+use crate::story::*;
 use regex::Regex;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, PartialEq)]
 pub enum LineType {
@@ -9,93 +15,503 @@ pub enum LineType {
     Constant,
     Comment,
     End,
+    Done,
+    Jump,
+    Stitch,
+    Gather,
+    Variable,
+    Logic,
+    Tag,
+    Character,
+    Command,
 }
 
 pub struct Line {
     pub text: String,
     pub type_: LineType,
+    // Line of the source file, starting from 1
+    pub line_number: usize,
+    // Byte range of the text inside the source
+    pub span: Range<usize>,
+    // Bytes of white space before the text
+    pub indentation: usize,
+    // Tags at the end of the line, without the hash
+    pub tags: Vec<String>,
+    // Index of the file in the reader, the first one is the main file
+    pub file: usize,
+    // Where each part of the text starts, in the text and after the indentation,
+    // since comments in the middle of the line are removed
+    pub offsets: Vec<(usize, usize)>,
+}
+
+// Lines that only end or divert the story, gathers can be followed by the same ones
+pub struct Diverts {
+    end: Regex,
+    done: Regex,
+    jump: Regex,
 }
 
 pub struct Reader {
     pub source: String,
+    // Files the lines come from, in the order they are included
+    pub files: Vec<PathBuf>,
     pub lines: Vec<Line>,
+    pub story: Story,
 }
 
 impl Line {
-    pub const fn new(text: String) -> Self {
+    pub const fn new(
+        text: String,
+        line_number: usize,
+        span: Range<usize>,
+        indentation: usize,
+    ) -> Self {
         Self {
             text,
             type_: LineType::Undefined,
+            line_number,
+            span,
+            indentation,
+            tags: Vec::new(),
+            file: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    // Columns of a part of the text inside the source line
+    pub fn columns(&self, range: Range<usize>) -> Range<usize> {
+        let column = |position: usize| {
+            let (text_start, source_start) = self
+                .offsets
+                .iter()
+                .rev()
+                .find(|(text_start, _)| *text_start <= position)
+                .copied()
+                .unwrap_or_default();
+
+            self.indentation + source_start + position - text_start
+        };
+
+        // The end is found from the last character, so it doesn't move after a comment
+        if range.is_empty() {
+            column(range.start)..column(range.start)
+        } else {
+            column(range.start)..column(range.end - 1) + 1
+        }
+    }
+
+    // Name of a knot or a stitch, without equal characters and white spaces
+    pub fn bookmark_name(&self) -> &str {
+        let chars_to_trim: &[char] = &['=', ' '];
+        self.text.trim_matches(chars_to_trim)
+    }
+
+    pub fn error(&self, code: ErrorCode, range: Range<usize>, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: self.file,
+            ..Diagnostic::error(code, self.line_number, self.columns(range), message)
+        }
+    }
+}
+
+impl Default for Diverts {
+    fn default() -> Self {
+        Self {
+            end: Regex::new(r"^->\s*END\s*$").unwrap(),
+            done: Regex::new(r"^->\s*DONE\s*$").unwrap(),
+            jump: Regex::new(r"^->\s*[\w.]+\s*$").unwrap(),
+        }
+    }
+}
+
+impl Diverts {
+    // End, Done or Jump, when the text is nothing but one of them
+    pub fn line_type(&self, text: &str) -> Option<LineType> {
+        if self.end.is_match(text) {
+            Some(LineType::End)
+        } else if self.done.is_match(text) {
+            Some(LineType::Done)
+        } else if self.jump.is_match(text) {
+            Some(LineType::Jump)
+        } else {
+            None
         }
     }
 }
 
 impl Reader {
+    // Included files are relative to the working directory
     pub fn from_text(source: &str) -> Self {
         Self {
             source: String::from(source),
+            files: vec![PathBuf::new()],
             lines: Vec::new(),
+            story: Story::default(),
         }
     }
 
-    pub fn parse_all_lines(&mut self) {
-        self.split_lines();
+    // Included files are relative to the directory of the file
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            files: vec![path.to_path_buf()],
+            ..Self::from_text(&fs::read_to_string(path)?)
+        })
+    }
+
+    pub fn parse_all_lines(&mut self) -> Result<(), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let source = self.source.clone();
+        let main_file = full_path(&self.files[0]);
+
+        self.read_source(&source, 0, &mut vec![main_file], &mut diagnostics);
         self.check_lines_type();
+
+        diagnostics.extend(self.check_undefined_lines());
+
+        let (story, story_diagnostics) = Story::from_lines(&self.lines);
+        self.story = story;
+        diagnostics.extend(story_diagnostics);
+
+        // Report errors in the order they appear in each file
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.file, diagnostic.line));
+
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    // Names of knots and stitches in order of appearance
+    // Stitches are prefixed with the name of their knot
+    pub fn bookmarks(&self) -> Vec<String> {
+        self.story.bookmarks()
+    }
+
+    // Included files take the place of the INCLUDE line
+    fn read_source(
+        &mut self,
+        source: &str,
+        file: usize,
+        including: &mut Vec<PathBuf>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        let re_include = Regex::new(r"^INCLUDE\s+(.+)$").unwrap();
+        let (source, comment_diagnostics) = Self::remove_block_comments(source);
+
+        diagnostics.extend(
+            comment_diagnostics
+                .into_iter()
+                .map(|diagnostic| Diagnostic { file, ..diagnostic }),
+        );
+
+        for line in Self::split_lines(&source, file) {
+            let caps = if let Some(caps) = re_include.captures(&line.text) {
+                caps
+            } else {
+                self.lines.push(line);
+                continue;
+            };
+
+            let name = caps.get(1).unwrap();
+            let directory = self.files[file].parent().unwrap_or_else(|| Path::new(""));
+            let path = directory.join(name.as_str());
+
+            if including.contains(&full_path(&path)) {
+                diagnostics.push(line.error(
+                    ErrorCode::IncludeCycle,
+                    name.range(),
+                    &format!("File {} includes itself", name.as_str()),
+                ));
+                continue;
+            }
+
+            match fs::read_to_string(&path) {
+                Ok(included_source) => {
+                    including.push(full_path(&path));
+                    self.files.push(path);
+
+                    let included_file = self.files.len() - 1;
+                    self.read_source(&included_source, included_file, including, diagnostics);
+
+                    including.pop();
+                }
+                Err(error) => diagnostics.push(line.error(
+                    ErrorCode::MissingInclude,
+                    name.range(),
+                    &format!("Cannot read file {}: {}", name.as_str(), error),
+                )),
+            }
+        }
+    }
+
+    // Block comments are replaced with NUL bytes, so that the position
+    // of everything else in the source stays the same. Like line comments,
+    // they don't start inside quotes or braces
+    fn remove_block_comments(text: &str) -> (String, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        let source = text.as_bytes();
+        let mut output = source.to_vec();
+        let mut index = 0;
+        let mut inside_quotes = false;
+        let mut inside_braces = 0;
+
+        while index < source.len() {
+            let outside = !inside_quotes && inside_braces == 0;
+
+            if outside && source[index..].starts_with(b"//") {
+                // Line comments are left to the line classification
+                while index < source.len() && source[index] != b'\n' {
+                    index += 1;
+                }
+            } else if outside && source[index..].starts_with(b"/*") {
+                let start = index;
+
+                index += 2;
+                while index < source.len() && !source[index..].starts_with(b"*/") {
+                    index += 1;
+                }
+
+                if index < source.len() {
+                    index += 2;
+                } else {
+                    let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+                    let line_number = text[..start].matches('\n').count() + 1;
+
+                    diagnostics.push(Diagnostic::error(
+                        ErrorCode::UnterminatedComment,
+                        line_number,
+                        start - line_start..start - line_start + 2,
+                        "Block comment is never closed",
+                    ));
+                }
+
+                // Keep new lines so the line numbers don't change
+                for byte in &mut output[start..index] {
+                    if *byte != b'\n' {
+                        *byte = b'\0';
+                    }
+                }
+            } else {
+                match source[index] {
+                    b'"' if inside_braces == 0 => inside_quotes = !inside_quotes,
+                    b'{' if !inside_quotes => inside_braces += 1,
+                    b'}' if !inside_quotes && inside_braces > 0 => inside_braces -= 1,
+                    // Quotes and braces never continue on the next line
+                    b'\n' => {
+                        inside_quotes = false;
+                        inside_braces = 0;
+                    }
+                    _ => (),
+                }
+
+                index += 1;
+            }
+        }
+
+        (String::from_utf8(output).unwrap(), diagnostics)
     }
 
-    fn split_lines(&mut self) {
-        // Split each string by newline
-        let lines: Vec<&str> = self.source.lines().collect();
+    fn split_lines(source: &str, file: usize) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut line_start = 0;
+
+        // Split each string by newline, keeping track of where it starts
+        for (index, line) in source.split('\n').enumerate() {
+            let line_end = line_start + line.len();
+
+            // Remove empty characters and comments from the start of the string
+            let chars_to_trim = |c: char| c.is_whitespace() || c == '\0';
+            let string_without_spaces = line.trim_start_matches(chars_to_trim);
+            let indentation = line.len() - string_without_spaces.len();
+
+            // Trailing comments are not part of the text
+            let string_without_spaces = match line_comment_start(string_without_spaces) {
+                Some(start) if start > 0 => &string_without_spaces[..start],
+                _ => string_without_spaces,
+            };
+
+            // Neither are trailing tags, but lines made only of tags are kept
+            let (string_without_spaces, tags) = match tag_start(string_without_spaces) {
+                Some(start) if start > 0 => (
+                    &string_without_spaces[..start],
+                    &string_without_spaces[start..],
+                ),
+                Some(_) => (string_without_spaces, string_without_spaces),
+                None => (string_without_spaces, ""),
+            };
+
+            // Remove them from the end too, including Windows line endings
+            let string_without_spaces = string_without_spaces.trim_end_matches(chars_to_trim);
 
-        // Add lines to the list
-        for line in lines {
             // Skips empty lines
-            if !line.is_empty() {
-                // Remove empty characters from the start of the string
-                let string_without_spaces = line.trim_start();
-                let string = Line::new(String::from(string_without_spaces));
-                self.lines.push(string);
+            if !string_without_spaces.is_empty() {
+                let start = line_start + indentation;
+                let mut text = String::new();
+                let mut offsets = Vec::new();
+                let mut part_start = 0;
+
+                // Comments in the middle of the line are removed, along with the
+                // white space they leave behind
+                for part in string_without_spaces.split('\0') {
+                    let part_end = part_start + part.len();
+
+                    let part = if text.ends_with(char::is_whitespace) {
+                        part.trim_start()
+                    } else {
+                        part
+                    };
+
+                    if !part.is_empty() {
+                        offsets.push((text.len(), part_end - part.len()));
+                        text.push_str(part);
+                    }
+
+                    part_start = part_end + 1;
+                }
+
+                let mut line = Line::new(
+                    text,
+                    index + 1,
+                    start..start + string_without_spaces.len(),
+                    indentation,
+                );
+
+                line.tags = tags
+                    .split('#')
+                    .map(|tag| tag.replace('\0', "").trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect();
+
+                line.file = file;
+                line.offsets = offsets;
+
+                lines.push(line);
             }
+
+            line_start = line_end + 1;
         }
+
+        lines
     }
 
     fn check_lines_type(&mut self) {
-        for line in &mut self.lines {
-            // If the line is empty it exits (but it shouldn't happen)
-            if line.text.is_empty() {
-                line.type_ = LineType::Undefined;
-                return;
-            }
+        let re_gather = Regex::new(r"^-(\s|$)").unwrap();
+        let diverts = Diverts::default();
+        let re_constant = Regex::new(r"^CONST\s").unwrap();
+        let re_variable = Regex::new(r"^VAR\s").unwrap();
+        let re_character = Regex::new(r"^CHARACTER\s").unwrap();
 
-            let char_ = if let Some(c) = line.text.as_bytes().get(0) {
+        for line in &mut self.lines {
+            let char_ = if let Some(c) = line.text.chars().next() {
                 c
             } else {
+                // Empty lines are skipped while splitting (but it shouldn't happen)
                 line.type_ = LineType::Undefined;
-                return;
+                continue;
             };
 
             match char_ {
-                b'a'..=b'z' | b'A'..=b'Z' | 0..=9 | b'"' | b'.' | b'\'' => {
-                    if line.text.starts_with("CONST") {
-                        line.type_ = LineType::Constant;
+                // Sticky and once-only choices
+                '+' | '*' => line.type_ = LineType::Question,
+                '=' => {
+                    // Knots start with at least two equal characters, stitches with one
+                    if line.text.starts_with("==") {
+                        line.type_ = LineType::Bookmark;
                     } else {
-                        line.type_ = LineType::Text;
+                        line.type_ = LineType::Stitch;
                     }
                 }
-                b'+' => line.type_ = LineType::Question,
-                b'=' => line.type_ = LineType::Bookmark,
-                b'/' => line.type_ = LineType::Comment,
-                // TODO: Must check between END and JUMP
-                b'-' => {
-                    let re_text = Regex::new(r"\s?+->\s+?END").unwrap();
-                    if re_text.is_match(&line.text) {
-                        line.type_ = LineType::End;
+                '/' if line.text.starts_with("//") => line.type_ = LineType::Comment,
+                '-' => {
+                    if re_gather.is_match(&line.text) {
+                        line.type_ = LineType::Gather;
+                    } else if let Some(type_) = diverts.line_type(&line.text) {
+                        line.type_ = type_;
                     } else {
                         line.type_ = LineType::Text;
                     }
                 }
+                '~' => line.type_ = LineType::Logic,
+                '#' => line.type_ = LineType::Tag,
+                '@' => line.type_ = LineType::Command,
+                _ if re_constant.is_match(&line.text) => {
+                    line.type_ = LineType::Constant;
+                }
+                _ if re_variable.is_match(&line.text) => {
+                    line.type_ = LineType::Variable;
+                }
+                _ if re_character.is_match(&line.text) => {
+                    line.type_ = LineType::Character;
+                }
+                // Any other printable character starts a line of text, whatever the language
+                _ if !char_.is_control() => line.type_ = LineType::Text,
                 _ => line.type_ = LineType::Undefined,
             }
         }
     }
+
+    fn check_undefined_lines(&self) -> Vec<Diagnostic> {
+        self.lines
+            .iter()
+            .filter(|line| line.type_ == LineType::Undefined)
+            .map(|line| {
+                line.error(
+                    ErrorCode::UndefinedLine,
+                    0..line.text.len(),
+                    "Line cannot be parsed",
+                )
+            })
+            .collect()
+    }
+}
+
+// Files are compared by their full path, when they exist
+fn full_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Start of the first tag, ignoring hashes inside quotes and braces
+fn tag_start(text: &str) -> Option<usize> {
+    if text.starts_with("//") {
+        return None;
+    }
+
+    let mut inside_quotes = false;
+    let mut inside_braces = 0;
+
+    for (index, char_) in text.char_indices() {
+        match char_ {
+            '"' if inside_braces == 0 => inside_quotes = !inside_quotes,
+            '{' if !inside_quotes => inside_braces += 1,
+            '}' if !inside_quotes && inside_braces > 0 => inside_braces -= 1,
+            '#' if !inside_quotes && inside_braces == 0 => return Some(index),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+// Start of a // comment, ignoring the ones inside quotes and constant references
+fn line_comment_start(text: &str) -> Option<usize> {
+    let mut inside_quotes = false;
+    let mut inside_braces = 0;
+    let mut previous_char = None;
+
+    for (index, char_) in text.char_indices() {
+        match char_ {
+            '"' if inside_braces == 0 => inside_quotes = !inside_quotes,
+            '{' if !inside_quotes => inside_braces += 1,
+            '}' if !inside_quotes && inside_braces > 0 => inside_braces -= 1,
+            '/' if !inside_quotes && inside_braces == 0 && previous_char == Some('/') => {
+                return Some(index - 1);
+            }
+            _ => (),
+        }
+
+        previous_char = Some(char_);
+    }
+
+    None
 }
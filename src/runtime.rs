@@ -0,0 +1,489 @@
+use std::collections::*;
+use crate::expression::*;
+use crate::story::Command;
+use crate::writer::{split_escaped, unescape};
+
+// A record of the .stevia file, with its fields still escaped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: usize,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paragraph {
+    pub text: String,
+    pub speaker: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Choice {
+    pub label: String,
+    pub target: usize,
+    // Once-only choices are remembered by their question and position
+    key: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoryPlayer {
+    pub records: Vec<Record>,
+    pub variables: HashMap<String, Token>,
+    pub characters: Vec<String>,
+    pub assets: Vec<String>,
+    // State of the random numbers used by shuffles
+    pub seed: u64,
+    position: usize,
+    visits: HashMap<usize, i32>,
+    sequences: HashMap<usize, usize>,
+    taken: HashSet<(usize, usize)>,
+    choices: Vec<Choice>,
+    tags: Vec<String>,
+    commands: Vec<Command>,
+    glue: bool,
+    done: bool,
+    ended: bool,
+}
+
+impl StoryPlayer {
+    pub fn load(input: &str) -> Result<Self, String> {
+        let mut player = Self {
+            seed: 0x2545_f491_4f6c_dd1d,
+            ..Self::default()
+        };
+
+        let mut offset = 0;
+
+        for record in split_escaped(input, '|') {
+            if input.is_empty() {
+                break;
+            }
+
+            let fields: Vec<String> = split_escaped(record, ';')
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+            match fields[0].as_str() {
+                // The headers are read once, before the story starts
+                "V" => {
+                    for pair in fields[1..].chunks(2) {
+                        let value = pair
+                            .get(1)
+                            .and_then(|value| Token::literal(&unescape(value)))
+                            .ok_or_else(|| malformed(offset))?;
+
+                        player.variables.insert(unescape(&pair[0]), value);
+                    }
+                }
+                "N" => player.characters = fields[1..].iter().map(|name| unescape(name)).collect(),
+                "R" => player.assets = fields[1..].iter().map(|name| unescape(name)).collect(),
+                "P" | "Q" | "J" | "E" | "D" | "A" | "L" | "I" | "G" | "C" | "S" | "T" | "B"
+                | "F" | "H" | "M" | "X" => {}
+                opcode => return Err(format!("Unknown record {} at {:05}", opcode, offset)),
+            }
+
+            player.records.push(Record { offset, fields });
+            offset += record.len() + 1;
+        }
+
+        Ok(player)
+    }
+
+    // Runs the story until the end of the next paragraph. Nothing is returned when
+    // the story waits for a choice, is done or has ended
+    pub fn continue_line(&mut self) -> Result<Option<Paragraph>, String> {
+        let mut paragraph: Option<Paragraph> = None;
+        // Without a choice the path cannot change, so a jump taken twice before any new
+        // paragraph would loop forever
+        let mut jumps = HashSet::new();
+
+        if self.ended || self.done || !self.choices.is_empty() {
+            return Ok(None);
+        }
+
+        while let Some(record) = self.records.get(self.position).cloned() {
+            let fields = &record.fields;
+            let opcode = fields[0].as_str();
+
+            // A new paragraph, its tags or commands end the current one, unless it's glued
+            let starts = matches!(opcode, "P" | "A" | "T" | "B" | "F" | "H" | "M" | "X");
+            let stops = matches!(opcode, "Q" | "E" | "D");
+
+            if paragraph.is_some() && (stops || (starts && !self.glue)) {
+                return Ok(paragraph);
+            }
+
+            *self.visits.entry(record.offset).or_default() += 1;
+            self.position += 1;
+
+            match opcode {
+                "P" | "A" => {
+                    let (speaker, text) = if opcode == "A" {
+                        let character = field(&record, 1)?
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|index| self.characters.get(index))
+                            .ok_or_else(|| malformed(record.offset))?;
+
+                        (Some(character.clone()), field(&record, 2)?)
+                    } else {
+                        (None, field(&record, 1)?)
+                    };
+
+                    if let Some(paragraph) = &mut paragraph {
+                        paragraph.text.push_str(&unescape(text));
+                    } else {
+                        jumps.clear();
+                        paragraph = Some(Paragraph {
+                            text: unescape(text),
+                            speaker,
+                            tags: std::mem::take(&mut self.tags),
+                        });
+                    }
+
+                    self.glue = false;
+                }
+                // Without text, the next paragraph continues the current one
+                "G" if field(&record, 1)?.is_empty() => self.glue = true,
+                "G" | "I" | "C" | "S" => {
+                    let text = self.inline_text(&record)?;
+
+                    if paragraph.is_none() {
+                        jumps.clear();
+                    }
+
+                    paragraph
+                        .get_or_insert_with(|| Paragraph {
+                            text: String::new(),
+                            speaker: None,
+                            tags: std::mem::take(&mut self.tags),
+                        })
+                        .text
+                        .push_str(&text);
+                }
+                "T" => {
+                    let tags = fields[1..].iter().map(|tag| unescape(tag));
+
+                    match &mut paragraph {
+                        Some(paragraph) => paragraph.tags.extend(tags),
+                        None => self.tags.extend(tags),
+                    }
+                }
+                "B" | "F" | "H" | "M" | "X" => {
+                    let command = self.command(&record)?;
+                    self.commands.push(command);
+                }
+                "L" => {
+                    let value = self.evaluate(field(&record, 2)?)?;
+                    self.variables.insert(unescape(field(&record, 1)?), value);
+                }
+                "J" => {
+                    let target = jump_place(&record, field(&record, 1)?)?;
+
+                    if !jumps.insert(target) {
+                        return Err(format!("Jump to {:05} loops forever", target));
+                    }
+
+                    self.jump(target)?;
+                }
+                "Q" => {
+                    // The question waits until a choice is taken
+                    self.position -= 1;
+                    self.choices = self.question(&record)?;
+
+                    // With no choice left the story cannot go on
+                    if self.choices.is_empty() {
+                        self.ended = true;
+                    }
+
+                    return Ok(None);
+                }
+                // The flow stops here until the player is moved somewhere else
+                "D" => {
+                    self.position -= 1;
+                    self.done = true;
+                    return Ok(None);
+                }
+                "E" => {
+                    self.ended = true;
+                    return Ok(None);
+                }
+                // Headers are read when loading
+                _ => {}
+            }
+        }
+
+        self.ended = true;
+        Ok(paragraph)
+    }
+
+    pub fn current_choices(&self) -> &[Choice] {
+        &self.choices
+    }
+
+    pub fn choose(&mut self, index: usize) -> Result<(), String> {
+        let choice = self
+            .choices
+            .get(index)
+            .cloned()
+            .ok_or_else(|| format!("There is no choice {}", index + 1))?;
+
+        if let Some(key) = choice.key {
+            self.taken.insert(key);
+        }
+
+        self.choices.clear();
+        self.jump(choice.target)
+    }
+
+    // Moves the story to a jump place, like the start of a knot
+    pub fn go_to(&mut self, target: usize) -> Result<(), String> {
+        self.jump(target)?;
+
+        self.choices.clear();
+        self.glue = false;
+        self.done = false;
+        self.ended = false;
+
+        Ok(())
+    }
+
+    // Commands met since the last time they were taken, in order
+    pub fn take_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    fn jump(&mut self, target: usize) -> Result<(), String> {
+        self.position = self
+            .records
+            .iter()
+            .position(|record| record.offset == target)
+            .ok_or_else(|| format!("There is no record at {:05}", target))?;
+
+        Ok(())
+    }
+
+    fn evaluate(&self, text: &str) -> Result<Token, String> {
+        let expression = Expression::from_rpn(&unescape(text))?;
+
+        expression.evaluate(|token| match token {
+            Token::Variable(name) => self.variables.get(name).cloned(),
+            Token::Visits(place) => place
+                .parse::<usize>()
+                .ok()
+                .map(|place| Token::Number(self.visits.get(&place).copied().unwrap_or_default())),
+            _ => None,
+        })
+    }
+
+    fn question(&mut self, record: &Record) -> Result<Vec<Choice>, String> {
+        let mut choices = Vec::new();
+
+        for (index, pair) in record.fields[1..].chunks(2).enumerate() {
+            let target = jump_place(record, pair.get(1).ok_or_else(|| malformed(record.offset))?)?;
+
+            // Markers are told apart from the text because they are not escaped
+            let label = &pair[0];
+            let once = label.starts_with('*');
+            let label = label.strip_prefix('*').unwrap_or(label);
+            let (condition, label) = split_condition(label);
+
+            if let Some(condition) = condition {
+                if !self.evaluate(&condition)?.is_true() {
+                    continue;
+                }
+            }
+
+            let key = (record.offset, index);
+
+            if once && self.taken.contains(&key) {
+                continue;
+            }
+
+            choices.push(Choice {
+                label: self.label(&unescape(label), record.offset)?,
+                target,
+                key: if once { Some(key) } else { None },
+            });
+        }
+
+        Ok(choices)
+    }
+
+    // Text that continues the current paragraph, or that is part of a label
+    fn inline_text(&mut self, record: &Record) -> Result<String, String> {
+        let text = match record.fields[0].as_str() {
+            "G" => unescape(field(record, 1)?),
+            "I" => {
+                let name = unescape(field(record, 1)?);
+                self.variables
+                    .get(&name)
+                    .map(Token::value_text)
+                    .ok_or_else(|| format!("{} has no value", name))?
+            }
+            "C" => {
+                let condition = self.evaluate(field(record, 1)?)?;
+                let text = if condition.is_true() {
+                    field(record, 2)?
+                } else {
+                    field(record, 3)?
+                };
+                unescape(text)
+            }
+            _ => self.sequence(record)?,
+        };
+
+        Ok(text)
+    }
+
+    fn sequence(&mut self, record: &Record) -> Result<String, String> {
+        let kind = field(record, 1)?;
+        let id = field(record, 2)?
+            .parse::<usize>()
+            .map_err(|_| malformed(record.offset))?;
+        let options = &record.fields[3..];
+
+        let count = self.sequences.entry(id).or_default();
+        let seen = *count;
+        *count += 1;
+
+        let option = match kind {
+            "stop" => options.get(seen.min(options.len().saturating_sub(1))),
+            "cycle" if !options.is_empty() => options.get(seen % options.len()),
+            "once" => options.get(seen),
+            "shuffle" if !options.is_empty() => {
+                let random = self.random();
+                options.get(random % options.len())
+            }
+            _ => None,
+        };
+
+        Ok(option.map(|option| unescape(option)).unwrap_or_default())
+    }
+
+    fn command(&self, record: &Record) -> Result<Command, String> {
+        let asset = |index: usize| -> Result<String, String> {
+            field(record, index)?
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| self.assets.get(index).cloned())
+                .ok_or_else(|| malformed(record.offset))
+        };
+
+        let command = match record.fields[0].as_str() {
+            "B" => Command::Background(asset(1)?),
+            "F" => {
+                let position = unescape(field(record, 3)?);
+
+                Command::Show {
+                    character: unescape(field(record, 1)?),
+                    asset: asset(2)?,
+                    position: if position.is_empty() {
+                        None
+                    } else {
+                        Some(position)
+                    },
+                }
+            }
+            "H" => Command::Hide(unescape(field(record, 1)?)),
+            "M" => Command::Music(asset(1)?),
+            _ => Command::Sound(asset(1)?),
+        };
+
+        Ok(command)
+    }
+
+    // Labels keep {name} for the runtime to put the value of the variable, and the
+    // fields of conditional and sequence records between braces
+    fn label(&mut self, label: &str, offset: usize) -> Result<String, String> {
+        let mut output = String::new();
+        let mut rest = label;
+
+        while let Some((start, end)) = rest
+            .find('{')
+            .and_then(|start| Some((start, start + rest[start..].find('}')?)))
+        {
+            output.push_str(&rest[..start]);
+
+            let fields: Vec<String> = split_escaped(&rest[start + 1..end], ';')
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+            match fields[0].as_str() {
+                "C" | "S" if fields.len() > 1 => {
+                    let text = self.inline_text(&Record { offset, fields })?;
+                    output.push_str(&text);
+                }
+                // Unknown names are left as they are
+                name => match self.variables.get(&unescape(name)) {
+                    Some(value) => output.push_str(&value.value_text()),
+                    None => output.push_str(&rest[start..=end]),
+                },
+            }
+
+            rest = &rest[end + 1..];
+        }
+
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    // Xorshift, good enough to shuffle some text
+    fn random(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        self.seed as usize
+    }
+}
+
+fn field(record: &Record, index: usize) -> Result<&str, String> {
+    record
+        .fields
+        .get(index)
+        .map(String::as_str)
+        .ok_or_else(|| malformed(record.offset))
+}
+
+fn jump_place(record: &Record, text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| malformed(record.offset))
+}
+
+fn malformed(offset: usize) -> String {
+    format!("Malformed record at {:05}", offset)
+}
+
+// The condition of a choice is between square brackets, which can be in its strings too
+fn split_condition(label: &str) -> (Option<String>, &str) {
+    if !label.starts_with('[') {
+        return (None, label);
+    }
+
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (position, char_) in label.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if char_ == '\\' {
+            escaped = true;
+        } else if char_ == '"' {
+            quoted = !quoted;
+        } else if char_ == ']' && !quoted {
+            return (Some(label[1..position].to_string()), &label[position + 1..]);
+        }
+    }
+
+    (None, label)
+}
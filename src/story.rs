@@ -0,0 +1,1133 @@
+use crate::diagnostic::*;
+use crate::expression::*;
+use crate::reader::*;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+const CHOICE_MARKERS: &[char] = &['+', '*'];
+const GATHER_MARKERS: &[char] = &['-'];
+// Where sprites can be shown
+const POSITIONS: &[&str] = &["left", "center", "right"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Text(String),
+    Constant(String),
+    // Printed with the value the variable has at that point of the story
+    Variable(String),
+    // One of the two texts, depending on the condition
+    Conditional(Condition, String, String),
+    // A different text each time it's printed
+    Sequence(SequenceKind, Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceKind {
+    // Stays on the last text
+    Stopping,
+    // Starts again after the last text
+    Cycle,
+    // Prints nothing after the last text
+    Once,
+    // Picks a random text every time
+    Shuffle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    // Full name of the knot or stitch
+    pub name: String,
+    // Where the target is written, to report errors
+    pub file: usize,
+    pub line: usize,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    // As it's written in the source, for the backends that cannot evaluate it
+    pub text: String,
+    pub expression: Expression,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Choice {
+    // Once-only choices disappear after being taken, sticky ones don't
+    pub once: bool,
+    // The choice is available only when the condition is true
+    pub condition: Option<Condition>,
+    // Text shown in the menu
+    pub label: Vec<Segment>,
+    // Text printed after the choice is taken, if any
+    pub output: Vec<Segment>,
+    // Where the story continues, if it doesn't continue with the body
+    pub target: Option<Target>,
+    // Content of the choice, that continues to the next gather
+    pub body: Vec<Node>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Paragraph(Vec<Segment>),
+    // A paragraph said by the character with that index
+    Dialogue(usize, Vec<Segment>),
+    Choices(Vec<Choice>),
+    Jump(Target),
+    End,
+    // Ends the current section of the story, the player waits for the next one
+    Done,
+    // Changes the scene, or plays music and sounds
+    Command(Command),
+    // Sets the variable to the result of the expression
+    Assignment(String, Expression),
+    // Tags of the node that follows, or of the knot when they are at its start
+    Tags(Vec<String>),
+    // The paragraph that follows continues the last one, even after a divert
+    Glue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Background(String),
+    // Sprites are shown in a slot named after the character, so they can be hidden later
+    Show {
+        character: String,
+        asset: String,
+        position: Option<String>,
+    },
+    Hide(String),
+    Music(String),
+    Sound(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub value: Token,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stitch {
+    // Stitches are prefixed with the name of their knot
+    pub name: String,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Knot {
+    pub name: String,
+    pub nodes: Vec<Node>,
+    pub stitches: Vec<Stitch>,
+}
+
+// Regexes used to parse the lines, built once for the whole story
+struct Patterns {
+    diverts: Diverts,
+    key: Regex,
+    label: Regex,
+    choice_jump: Regex,
+    condition: Regex,
+    assignment: Regex,
+    jump: Regex,
+}
+
+// What parsing a line needs to know about the rest of the story
+struct Context<'a> {
+    patterns: &'a Patterns,
+    // Knots and stitches can be used before they are declared
+    bookmarks: &'a [String],
+    // Knot of the line, its stitches can be referred by their name alone
+    knot: Option<&'a str>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Story {
+    pub constants: HashMap<String, String>,
+    // Variables in order of declaration, with their initial value
+    pub variables: Vec<Variable>,
+    // Characters in order of declaration, dialogue refers to them by index
+    pub characters: Vec<String>,
+    // Content that comes before the first knot
+    pub nodes: Vec<Node>,
+    pub knots: Vec<Knot>,
+}
+
+impl Node {
+    // Whether the story never continues with what comes after the node
+    pub fn diverts(&self) -> bool {
+        match self {
+            Self::Paragraph(_)
+            | Self::Dialogue(_, _)
+            | Self::Assignment(_, _)
+            | Self::Tags(_)
+            | Self::Glue
+            | Self::Command(_) => false,
+            Self::Choices(choices) => choices.iter().all(Choice::diverts),
+            Self::Jump(_) | Self::End | Self::Done => true,
+        }
+    }
+}
+
+impl Command {
+    pub fn asset(&self) -> Option<&str> {
+        match self {
+            Self::Background(asset) | Self::Music(asset) | Self::Sound(asset) => Some(asset),
+            Self::Show { asset, .. } => Some(asset),
+            Self::Hide(_) => None,
+        }
+    }
+}
+
+impl Choice {
+    // Whether the story continues somewhere else once the choice is taken
+    pub fn diverts(&self) -> bool {
+        self.target.is_some() || matches!(self.body.last(), Some(node) if node.diverts())
+    }
+
+    // Nodes that follow the choice once it's taken, the output and then the body
+    pub fn nodes(&self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+
+        if !self.output.is_empty() {
+            nodes.push(Node::Paragraph(self.output.clone()));
+        }
+
+        nodes.extend(self.body.iter().cloned());
+        nodes
+    }
+}
+
+impl Patterns {
+    fn new() -> Self {
+        Self {
+            diverts: Diverts::default(),
+            key: Regex::new(r"\{(?P<key>.*?)\}").unwrap(),
+            label: Regex::new(r"\[(.*?)\]").unwrap(),
+            choice_jump: Regex::new(r"\->\s+(.*)$").unwrap(),
+            condition: Regex::new(r"^\s*\{([^}]*)\}").unwrap(),
+            assignment: Regex::new(r"^~\s*(\w+)\s*(=|\+=|-=|\+\+|--)\s*(.*)$").unwrap(),
+            jump: Regex::new(r"\->\s*(.*)$").unwrap(),
+        }
+    }
+}
+
+impl Story {
+    pub fn from_lines(lines: &[Line]) -> (Self, Vec<Diagnostic>) {
+        let mut story = Self::default();
+        let mut diagnostics = Vec::new();
+
+        // Constants and bookmarks can be used before they are declared
+        let bookmarks = bookmark_names(lines);
+        let patterns = Patterns::new();
+        story.process_constants(lines, &mut diagnostics);
+        story.process_variables(lines, &mut diagnostics);
+        story.process_characters(lines);
+
+        let mut knot = None;
+        // How many groups of choices are nested at this point, and whether lines
+        // belong to the body of the last choice
+        let mut open_choices = 0;
+        let mut inside_body = false;
+        // Full names of the knots and stitches declared so far
+        let mut declared = HashSet::new();
+
+        for line in lines {
+            let context = Context {
+                patterns: &patterns,
+                bookmarks: &bookmarks,
+                knot,
+            };
+
+            // Gathers can be followed by text, that works like any other line of text
+            let text_range = match line.type_ {
+                LineType::Text => Some(0..line.text.len()),
+                LineType::Gather => gather_text(line, &context),
+                _ => None,
+            };
+
+            let text = text_range
+                .as_ref()
+                .map_or("", |range| &line.text[range.clone()]);
+            let glue_before = text.starts_with("<>");
+            let glue_after = text.len() > 2 && text.ends_with("<>");
+
+            // Glue is not part of the text
+            let text_range = text_range.map(|range| {
+                let start = range.start + if glue_before { 2 } else { 0 };
+                let end = range.end - if glue_after { 2 } else { 0 };
+                start..end.max(start)
+            });
+
+            let node = match line.type_ {
+                LineType::Text => {
+                    story.parse_text(line, text_range.unwrap(), &context, &mut diagnostics)
+                }
+                LineType::Question => {
+                    let choice = story.parse_choice(line, &context, &mut diagnostics);
+
+                    if let Some(choice) = choice {
+                        // A choice can be at most one level deeper than the last one
+                        let (level, _) = nesting(&line.text, CHOICE_MARKERS);
+                        let level = level.min(open_choices + 1);
+
+                        // Choices with a divert have nothing else to do
+                        inside_body = !choice.diverts();
+                        story.push_choice(choice, level, level <= open_choices);
+                        open_choices = level;
+                    }
+
+                    None
+                }
+                LineType::Gather => {
+                    // Gathers are where the choices of their level join back
+                    let (level, _) = nesting(&line.text, GATHER_MARKERS);
+                    open_choices = open_choices.min(level - 1);
+                    inside_body = true;
+
+                    match text_range {
+                        Some(range) => story.parse_text(line, range, &context, &mut diagnostics),
+                        None => parse_gather(line, &context),
+                    }
+                }
+                LineType::Bookmark => {
+                    open_choices = 0;
+                    knot = Some(line.bookmark_name());
+                    declare_bookmark(line, line.bookmark_name(), &mut declared, &mut diagnostics);
+
+                    story.knots.push(Knot {
+                        name: line.bookmark_name().to_string(),
+                        ..Knot::default()
+                    });
+
+                    None
+                }
+                LineType::Stitch => {
+                    open_choices = 0;
+
+                    let stitch = Stitch {
+                        name: qualified_name(knot, line.bookmark_name()),
+                        ..Stitch::default()
+                    };
+                    declare_bookmark(line, &stitch.name, &mut declared, &mut diagnostics);
+
+                    // A stitch outside of a knot works like a knot
+                    match story.knots.last_mut() {
+                        Some(last_knot) if knot.is_some() => last_knot.stitches.push(stitch),
+                        _ => story.knots.push(Knot {
+                            name: stitch.name,
+                            ..Knot::default()
+                        }),
+                    }
+
+                    None
+                }
+                LineType::End => Some(Node::End),
+                LineType::Done => Some(Node::Done),
+                LineType::Command => parse_command(line, &mut diagnostics),
+                LineType::Jump => Some(Node::Jump(parse_jump(line, &context))),
+                LineType::Logic => story.parse_assignment(line, &context, &mut diagnostics),
+                // Lines of tags are added with the tags at the end of the other lines
+                LineType::Tag => None,
+                // Undefined lines are reported by the reader
+                LineType::Undefined
+                | LineType::Constant
+                | LineType::Variable
+                | LineType::Character
+                | LineType::Comment => None,
+            };
+
+            // Tags come before what they annotate, choices keep their own
+            let tags = (line.type_ != LineType::Question && !line.tags.is_empty())
+                .then(|| Node::Tags(line.tags.clone()));
+
+            let nodes = glue_before
+                .then_some(Node::Glue)
+                .into_iter()
+                .chain(tags)
+                .chain(node)
+                .chain(glue_after.then_some(Node::Glue));
+
+            for node in nodes {
+                // Choices with a divert have no body, so anything after them ends the group
+                if !inside_body {
+                    open_choices -= usize::from(open_choices > 0);
+                    inside_body = true;
+                }
+
+                story.push_node(open_choices, node);
+            }
+        }
+
+        (story, diagnostics)
+    }
+
+    // Images and sounds used by the commands, in order of appearance
+    pub fn assets(&self) -> Vec<String> {
+        let mut assets = Vec::new();
+
+        collect_assets(&self.nodes, &mut assets);
+
+        for knot in &self.knots {
+            collect_assets(&knot.nodes, &mut assets);
+
+            for stitch in &knot.stitches {
+                collect_assets(&stitch.nodes, &mut assets);
+            }
+        }
+
+        assets
+    }
+
+    // Names of knots and stitches in order of appearance
+    pub fn bookmarks(&self) -> Vec<String> {
+        let mut bookmarks = Vec::new();
+
+        for knot in &self.knots {
+            bookmarks.push(knot.name.clone());
+
+            for stitch in &knot.stitches {
+                bookmarks.push(stitch.name.clone());
+            }
+        }
+
+        bookmarks
+    }
+
+    // Joins the segments, replacing constants with their value and variables
+    // with their initial value, nothing has been visited or printed yet
+    pub fn text(&self, segments: &[Segment]) -> String {
+        let mut output = String::new();
+
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => output.push_str(text),
+                Segment::Constant(key) => {
+                    if let Some(value) = self.constants.get(key) {
+                        output.push_str(value);
+                    }
+                }
+                Segment::Variable(name) => {
+                    if let Some(variable) = self.variable(name) {
+                        output.push_str(&variable.value.value_text());
+                    }
+                }
+                Segment::Conditional(condition, then, otherwise) => {
+                    let value = condition.expression.evaluate(|token| match token {
+                        Token::Variable(name) => self.variable(name).map(|v| v.value.clone()),
+                        _ => Some(Token::Number(0)),
+                    });
+
+                    if matches!(value, Ok(value) if value.is_true()) {
+                        output.push_str(then);
+                    } else {
+                        output.push_str(otherwise);
+                    }
+                }
+                // The first time, every kind of sequence prints the first text
+                Segment::Sequence(_, options) => output.push_str(&options[0]),
+            }
+        }
+
+        output
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|variable| variable.name == name)
+    }
+
+    fn current_nodes(&mut self) -> &mut Vec<Node> {
+        match self.knots.last_mut() {
+            Some(knot) => match knot.stitches.last_mut() {
+                Some(stitch) => &mut stitch.nodes,
+                None => &mut knot.nodes,
+            },
+            None => &mut self.nodes,
+        }
+    }
+
+    // Nodes in the body of the last choice of each nested group, up to the level
+    fn nodes_at(&mut self, level: usize) -> &mut Vec<Node> {
+        let mut nodes = self.current_nodes();
+
+        for _ in 0..level {
+            // A level is opened only after adding a choice to it
+            nodes = match nodes.last_mut() {
+                Some(Node::Choices(choices)) => &mut choices.last_mut().unwrap().body,
+                _ => unreachable!(),
+            };
+        }
+
+        nodes
+    }
+
+    // Paragraphs glued to the one before them become a single paragraph
+    fn push_node(&mut self, level: usize, node: Node) {
+        let nodes = self.nodes_at(level);
+        let glued = matches!(
+            nodes.as_slice(),
+            [.., Node::Paragraph(_) | Node::Dialogue(_, _), Node::Glue]
+        );
+
+        match node {
+            Node::Paragraph(segments) if glued => {
+                nodes.pop();
+
+                if let Some(Node::Paragraph(previous) | Node::Dialogue(_, previous)) =
+                    nodes.last_mut()
+                {
+                    previous.extend(segments);
+                }
+            }
+            Node::Glue if matches!(nodes.last(), Some(Node::Glue)) => {}
+            node => nodes.push(node),
+        }
+    }
+
+    fn push_choice(&mut self, choice: Choice, level: usize, inside_group: bool) {
+        let nodes = self.nodes_at(level - 1);
+
+        match nodes.last_mut() {
+            Some(Node::Choices(choices)) if inside_group => choices.push(choice),
+            _ => nodes.push(Node::Choices(vec![choice])),
+        }
+    }
+
+    fn process_constants(&mut self, lines: &[Line], diagnostics: &mut Vec<Diagnostic>) {
+        let re_key = Regex::new(r#" ((?:\\.|[^"\\])*) ="#).unwrap();
+        let re_value = Regex::new(r#""((?:\\.|[^"\\])*)""#).unwrap();
+
+        for line in lines.iter().filter(|line| line.type_ == LineType::Constant) {
+            let re_key = re_key.captures(&line.text);
+            let re_value = re_value.captures(&line.text);
+
+            match (re_key, re_value) {
+                (Some(re_key), Some(re_value)) => {
+                    // Remove leading and trailing spaces
+                    let const_name = re_key[1].trim().to_string();
+                    let const_value = re_value[1].trim().to_string();
+
+                    // Insert constant to table
+                    self.constants.insert(const_name, const_value);
+                }
+                (None, _) => diagnostics.push(line.error(
+                    ErrorCode::MalformedConstant,
+                    0..line.text.len(),
+                    "Cannot get key while parsing constant",
+                )),
+                (_, None) => diagnostics.push(line.error(
+                    ErrorCode::MalformedConstant,
+                    0..line.text.len(),
+                    "Cannot get value while parsing constant",
+                )),
+            }
+        }
+    }
+
+    fn process_variables(&mut self, lines: &[Line], diagnostics: &mut Vec<Diagnostic>) {
+        let re_variable = Regex::new(r"^VAR\s+(\w+)\s*=\s*(.*)$").unwrap();
+
+        for line in lines.iter().filter(|line| line.type_ == LineType::Variable) {
+            let caps = if let Some(caps) = re_variable.captures(&line.text) {
+                caps
+            } else {
+                diagnostics.push(line.error(
+                    ErrorCode::MalformedVariable,
+                    0..line.text.len(),
+                    "Cannot get name while parsing variable",
+                ));
+                continue;
+            };
+
+            let value = caps.get(2).unwrap();
+
+            // Variables start with a number, a boolean or a string
+            if let Some(value) = Token::literal(value.as_str()) {
+                self.variables.push(Variable {
+                    name: caps[1].to_string(),
+                    value,
+                });
+            } else {
+                diagnostics.push(line.error(
+                    ErrorCode::MalformedVariable,
+                    value.range(),
+                    "Cannot get value while parsing variable",
+                ));
+            }
+        }
+    }
+
+    fn process_characters(&mut self, lines: &[Line]) {
+        let re_character = Regex::new(r"^CHARACTER\s+(.*)$").unwrap();
+
+        for line in lines
+            .iter()
+            .filter(|line| line.type_ == LineType::Character)
+        {
+            let names = re_character.captures(&line.text).unwrap();
+
+            // More characters can be declared in the same line
+            for name in names[1].split(',').map(str::trim) {
+                if !name.is_empty() && !self.characters.iter().any(|c| c == name) {
+                    self.characters.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    // Index of the character that says the text, and where what they say starts
+    fn speaker(&self, text: &str) -> Option<(usize, usize)> {
+        let (name, said) = text.split_once(':')?;
+        let character = self.characters.iter().position(|c| c == name.trim())?;
+
+        Some((character, text.len() - said.trim_start().len()))
+    }
+
+    // Splits the text in plain text, constants and variables, the offset is where
+    // the text starts inside the line
+    fn parse_segments(
+        &self,
+        line: &Line,
+        text: &str,
+        offset: usize,
+        context: &Context,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut last_end = 0;
+
+        for caps in context.patterns.key.captures_iter(text) {
+            let whole_key = caps.get(0).unwrap();
+            let key = caps["key"].to_string();
+
+            if whole_key.start() > last_end {
+                segments.push(Segment::Text(text[last_end..whole_key.start()].to_string()));
+            }
+
+            let kind = match key.chars().next() {
+                Some('&') => Some(SequenceKind::Cycle),
+                Some('!') => Some(SequenceKind::Once),
+                Some('~') => Some(SequenceKind::Shuffle),
+                _ if key.contains('|') && !key.contains(':') => Some(SequenceKind::Stopping),
+                _ => None,
+            };
+
+            if let Some(kind) = kind {
+                // Sequences are marked by their first character, except the stopping ones
+                let options = if kind == SequenceKind::Stopping {
+                    &key
+                } else {
+                    &key[1..]
+                };
+
+                segments.push(Segment::Sequence(
+                    kind,
+                    options
+                        .split('|')
+                        .map(|option| option.trim().to_string())
+                        .collect(),
+                ));
+            } else if let Some(colon) = key.find(':') {
+                // The text after the pipe is printed when the condition is false
+                let condition = &key[..colon];
+                let start = offset + caps.name("key").unwrap().start();
+                let (then, otherwise) = match key[colon + 1..].split_once('|') {
+                    Some((then, otherwise)) => (then, otherwise),
+                    None => (&key[colon + 1..], ""),
+                };
+
+                let expression = self.parse_expression(
+                    line,
+                    condition,
+                    start..start + colon,
+                    context,
+                    diagnostics,
+                );
+
+                if let Some(expression) = expression {
+                    segments.push(Segment::Conditional(
+                        Condition {
+                            text: condition.trim().to_string(),
+                            expression,
+                        },
+                        then.trim().to_string(),
+                        otherwise.trim().to_string(),
+                    ));
+                }
+            } else if self.constants.contains_key(&key) {
+                segments.push(Segment::Constant(key));
+            } else if self.variable(&key).is_some() {
+                segments.push(Segment::Variable(key));
+            } else {
+                diagnostics.push(line.error(
+                    ErrorCode::UndefinedConstant,
+                    offset + whole_key.start()..offset + whole_key.end(),
+                    &format!("Constant {} is not defined", key),
+                ));
+                segments.push(Segment::Constant(key));
+            }
+
+            last_end = whole_key.end();
+        }
+
+        if last_end < text.len() {
+            segments.push(Segment::Text(text[last_end..].to_string()));
+        }
+
+        segments
+    }
+
+    fn parse_choice(
+        &self,
+        line: &Line,
+        context: &Context,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Choice> {
+        // Check after arrow
+        let re_jump = context.patterns.choice_jump.captures(&line.text);
+
+        // Choices without a divert continue with their body
+        let text_end = re_jump
+            .as_ref()
+            .map_or(line.text.len(), |re_jump| re_jump.get(0).unwrap().start());
+
+        let (_, mut text_start) = nesting(&line.text, CHOICE_MARKERS);
+
+        // Conditions come before the text of the choice
+        let mut condition: Option<Condition> = None;
+        let mut valid = true;
+
+        while let Some(caps) = context
+            .patterns
+            .condition
+            .captures(&line.text[text_start..])
+        {
+            let text = caps.get(1).unwrap();
+            let range = text_start + text.start()..text_start + text.end();
+            text_start += caps.get(0).unwrap().end();
+
+            let expression =
+                self.parse_expression(line, text.as_str(), range, context, diagnostics);
+
+            match (expression, condition.as_mut()) {
+                (None, _) => valid = false,
+                // All the conditions must be true
+                (Some(expression), Some(condition)) => {
+                    condition.text = format!("{} and {}", condition.text, text.as_str().trim());
+                    condition.expression.tokens.extend(expression.tokens);
+                    condition.expression.tokens.push(Token::Operator("and"));
+                }
+                (Some(expression), None) => {
+                    condition = Some(Condition {
+                        text: text.as_str().trim().to_string(),
+                        expression,
+                    });
+                }
+            }
+        }
+
+        if !valid {
+            return None;
+        }
+
+        // Check between brackets, after the conditions since their strings can contain brackets
+        let rest = &line.text[text_start..];
+        let re_text = if let Some(re_text) = context.patterns.label.captures(rest) {
+            re_text
+        } else {
+            diagnostics.push(line.error(
+                ErrorCode::MalformedQuestion,
+                0..line.text.len(),
+                "Cannot get key of question",
+            ));
+            return None;
+        };
+
+        let whole_brackets = re_text.get(0).unwrap();
+        let brackets = text_start + whole_brackets.start()..text_start + whole_brackets.end();
+        let label = re_text.get(1).unwrap();
+        let label_start = text_start + label.start();
+
+        let before = trim_range(&line.text, text_start..brackets.start);
+        let after = trim_range(&line.text, brackets.end..text_end.max(brackets.end));
+
+        let before_text = self.parse_segments(
+            line,
+            &line.text[before.clone()],
+            before.start,
+            context,
+            diagnostics,
+        );
+        let label_text =
+            self.parse_segments(line, label.as_str(), label_start, context, diagnostics);
+        let after_text = self.parse_segments(
+            line,
+            &line.text[after.clone()],
+            after.start,
+            context,
+            diagnostics,
+        );
+
+        // Text before the brackets is shown both in the menu and in the output,
+        // text after the brackets only in the output
+        let mut menu_label = before_text.clone();
+        let mut output = before_text;
+
+        if !before.is_empty() {
+            menu_label.push(Segment::Text(
+                line.text[before.end..brackets.start].to_string(),
+            ));
+        }
+        menu_label.extend(label_text);
+
+        // Keep the white space between the two parts, only once
+        if !before.is_empty() && !after.is_empty() {
+            let before_space = &line.text[before.end..brackets.start];
+            let after_space = &line.text[brackets.end..after.start];
+
+            output.push(Segment::Text(if before_space.is_empty() {
+                after_space.to_string()
+            } else {
+                before_space.to_string()
+            }));
+        }
+        output.extend(after_text);
+
+        // Choices that end the story have the end as their body
+        let (target, body) = match re_jump.as_ref().map(|re_jump| re_jump[1].trim()) {
+            Some("END") => (None, vec![Node::End]),
+            Some("DONE") => (None, vec![Node::Done]),
+            _ => (
+                re_jump.map(|re_jump| parse_target(line, &re_jump.get(1).unwrap(), context)),
+                Vec::new(),
+            ),
+        };
+
+        Some(Choice {
+            once: line.text.starts_with('*'),
+            condition,
+            label: menu_label,
+            output,
+            target,
+            body,
+            tags: line.tags.clone(),
+        })
+    }
+
+    // Assignments can also increase or decrease the variable
+    fn parse_assignment(
+        &self,
+        line: &Line,
+        context: &Context,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Node> {
+        let caps = if let Some(caps) = context.patterns.assignment.captures(&line.text) {
+            caps
+        } else {
+            diagnostics.push(line.error(
+                ErrorCode::MalformedExpression,
+                0..line.text.len(),
+                "Cannot parse assignment",
+            ));
+            return None;
+        };
+
+        let name = caps.get(1).unwrap();
+        let value = caps.get(3).unwrap();
+
+        let expression = match &caps[2] {
+            "++" if value.as_str().is_empty() => format!("{} + 1", name.as_str()),
+            "--" if value.as_str().is_empty() => format!("{} - 1", name.as_str()),
+            "+=" => format!("{} + ({})", name.as_str(), value.as_str()),
+            "-=" => format!("{} - ({})", name.as_str(), value.as_str()),
+            "=" => value.as_str().to_string(),
+            _ => {
+                diagnostics.push(line.error(
+                    ErrorCode::MalformedExpression,
+                    value.range(),
+                    "Unexpected text after assignment",
+                ));
+                return None;
+            }
+        };
+
+        // The expression can use the variable too, so it's reported only once
+        if self.variable(name.as_str()).is_none() {
+            diagnostics.push(line.error(
+                ErrorCode::UndefinedVariable,
+                name.range(),
+                &format!("Variable {} is not defined", name.as_str()),
+            ));
+            return None;
+        }
+
+        let expression =
+            self.parse_expression(line, &expression, value.range(), context, diagnostics)?;
+
+        Some(Node::Assignment(name.as_str().to_string(), expression))
+    }
+
+    // Names that are not variables are the visit counts of knots and stitches,
+    // the range is where the expression is written inside the line
+    fn parse_expression(
+        &self,
+        line: &Line,
+        text: &str,
+        range: Range<usize>,
+        context: &Context,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Expression> {
+        let mut expression = match Expression::parse(text) {
+            Ok(expression) => expression,
+            Err(message) => {
+                diagnostics.push(line.error(ErrorCode::MalformedExpression, range, &message));
+                return None;
+            }
+        };
+
+        let mut valid = true;
+
+        for token in &mut expression.tokens {
+            if let Token::Variable(name) = token {
+                if self.variable(name).is_some() {
+                    continue;
+                }
+
+                let bookmark = resolve_target(context.bookmarks, context.knot, name);
+
+                if context.bookmarks.contains(&bookmark) {
+                    *token = Token::Visits(bookmark);
+                } else {
+                    diagnostics.push(line.error(
+                        ErrorCode::UndefinedVariable,
+                        range.clone(),
+                        &format!("Variable {} is not defined", name),
+                    ));
+                    valid = false;
+                }
+            }
+        }
+
+        valid.then_some(expression)
+    }
+
+    // A paragraph, or what a character says, the range is where the text is inside the line
+    fn parse_text(
+        &self,
+        line: &Line,
+        range: Range<usize>,
+        context: &Context,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Node> {
+        let mut start = range.start;
+        let speaker = self.speaker(&line.text[range.clone()]);
+
+        // The name of the speaker is not part of the text
+        if let Some((_, text_start)) = speaker {
+            start += text_start;
+        }
+
+        let text = &line.text[start..range.end];
+        let segments = self.parse_segments(line, text, start, context, diagnostics);
+
+        match speaker {
+            Some((character, _)) => Some(Node::Dialogue(character, segments)),
+            None if text.is_empty() => None,
+            None => Some(Node::Paragraph(segments)),
+        }
+    }
+}
+
+// Names of knots and stitches in order of appearance, before building the story
+fn bookmark_names(lines: &[Line]) -> Vec<String> {
+    let mut knot = None;
+    let mut bookmarks = Vec::new();
+
+    for line in lines {
+        match line.type_ {
+            LineType::Bookmark => {
+                knot = Some(line.bookmark_name());
+                bookmarks.push(line.bookmark_name().to_string());
+            }
+            LineType::Stitch => bookmarks.push(qualified_name(knot, line.bookmark_name())),
+            _ => (),
+        }
+    }
+
+    bookmarks
+}
+
+// Where the text after the gather markers is, if it's not a divert or the end of the story
+fn gather_text(line: &Line, context: &Context) -> Option<Range<usize>> {
+    let (_, text_start) = nesting(&line.text, GATHER_MARKERS);
+    let range = trim_range(&line.text, text_start..line.text.len());
+    let text = &line.text[range.clone()];
+
+    (!text.is_empty() && context.patterns.diverts.line_type(text).is_none()).then_some(range)
+}
+
+// Gathers can be followed by a divert or the end of the story
+fn parse_gather(line: &Line, context: &Context) -> Option<Node> {
+    let (_, text_start) = nesting(&line.text, GATHER_MARKERS);
+
+    match context
+        .patterns
+        .diverts
+        .line_type(line.text[text_start..].trim())?
+    {
+        LineType::End => Some(Node::End),
+        LineType::Done => Some(Node::Done),
+        _ => Some(Node::Jump(parse_jump(line, context))),
+    }
+}
+
+// Jumps could not tell apart knots or stitches with the same name
+fn declare_bookmark(
+    line: &Line,
+    name: &str,
+    declared: &mut HashSet<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if !declared.insert(name.to_string()) {
+        let start = line.text.find(line.bookmark_name()).unwrap_or_default();
+
+        diagnostics.push(line.error(
+            ErrorCode::DuplicateBookmark,
+            start..start + line.bookmark_name().len(),
+            &format!("Bookmark {} is already defined", name),
+        ));
+    }
+}
+
+fn collect_assets(nodes: &[Node], assets: &mut Vec<String>) {
+    for node in nodes {
+        match node {
+            Node::Command(command) => {
+                if let Some(asset) = command.asset() {
+                    if !assets.iter().any(|a| a == asset) {
+                        assets.push(asset.to_string());
+                    }
+                }
+            }
+            Node::Choices(choices) => {
+                for choice in choices {
+                    collect_assets(&choice.body, assets);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Commands are a name followed by words, like "@show anna happy left"
+fn parse_command(line: &Line, diagnostics: &mut Vec<Diagnostic>) -> Option<Node> {
+    let mut words = line.text[1..].split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let words: Vec<&str> = words.collect();
+
+    let command = match (name, words.as_slice()) {
+        ("bg", [asset]) => Command::Background(asset.to_string()),
+        ("music", [asset]) => Command::Music(asset.to_string()),
+        ("sfx", [asset]) => Command::Sound(asset.to_string()),
+        ("hide", [character]) => Command::Hide(character.to_string()),
+        ("show", [character, rest @ ..]) if rest.len() <= 2 => {
+            // The position is the last word, when it's one of the known ones
+            let (rest, position) = match rest {
+                [rest @ .., position] if POSITIONS.contains(position) => {
+                    (rest, Some(position.to_string()))
+                }
+                _ => (rest, None),
+            };
+
+            // Each expression of a character is a different asset
+            let asset = match rest {
+                [] => character.to_string(),
+                [expression] => format!("{}_{}", character, expression),
+                _ => return malformed_command(line, diagnostics),
+            };
+
+            Command::Show {
+                character: character.to_string(),
+                asset,
+                position,
+            }
+        }
+        _ => return malformed_command(line, diagnostics),
+    };
+
+    Some(Node::Command(command))
+}
+
+fn malformed_command(line: &Line, diagnostics: &mut Vec<Diagnostic>) -> Option<Node> {
+    diagnostics.push(line.error(
+        ErrorCode::MalformedCommand,
+        0..line.text.len(),
+        "Cannot parse command",
+    ));
+
+    None
+}
+
+fn parse_jump(line: &Line, context: &Context) -> Target {
+    let re_jump = context.patterns.jump.captures(&line.text).unwrap();
+
+    parse_target(line, &re_jump.get(1).unwrap(), context)
+}
+
+fn parse_target(line: &Line, target: &regex::Match, context: &Context) -> Target {
+    let name = target.as_str().trim();
+
+    Target {
+        name: resolve_target(context.bookmarks, context.knot, name),
+        file: line.file,
+        line: line.line_number,
+        span: line.columns(target.start()..target.start() + name.len()),
+    }
+}
+
+fn qualified_name(knot: Option<&str>, stitch: &str) -> String {
+    match knot {
+        Some(knot) => format!("{}.{}", knot, stitch),
+        None => stitch.to_string(),
+    }
+}
+
+// Nesting level of a choice or a gather, and where the text after the markers starts
+fn nesting(text: &str, markers: &[char]) -> (usize, usize) {
+    let mut level = 0;
+    let mut text_start = 0;
+
+    for (index, char_) in text.char_indices() {
+        // Gathers can be followed by a divert
+        if markers.contains(&char_) && !text[index..].starts_with("->") {
+            level += 1;
+            text_start = index + 1;
+        } else if !char_.is_whitespace() {
+            break;
+        }
+    }
+
+    (level, text_start)
+}
+
+// Range of the text without the white space around it
+fn trim_range(text: &str, range: Range<usize>) -> Range<usize> {
+    let slice = &text[range.clone()];
+    let start = range.start + slice.len() - slice.trim_start().len();
+    let end = range.start + slice.trim_end().len();
+
+    start..end.max(start)
+}
+
+// A stitch can be referred by its name alone inside its own knot
+fn resolve_target(bookmarks: &[String], knot: Option<&str>, target: &str) -> String {
+    if !target.contains('.') {
+        let local_name = qualified_name(knot, target);
+        if bookmarks.contains(&local_name) {
+            return local_name;
+        }
+    }
+
+    target.to_string()
+}
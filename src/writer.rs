@@ -1,6 +1,8 @@
-use regex::Regex;
 use std::collections::*;
+use crate::diagnostic::*;
+use crate::expression::*;
 use crate::reader::*;
+use crate::story::*;
 
 #[derive(Default)]
 pub struct Writer {
@@ -9,6 +11,12 @@ pub struct Writer {
     pub symbols: HashMap<String, usize>,
     pub branch_table: HashMap<String, Vec<usize>>,
     pub constants: HashMap<String, String>,
+    // Each sequence has its own counter in the runtime
+    pub sequences: usize,
+    // Commands refer to the assets by their position in the table
+    pub assets: Vec<String>,
+    // Knots and stitches used by jumps and choices, to check they exist
+    pub targets: Vec<Target>,
 }
 
 impl Writer {
@@ -19,6 +27,9 @@ impl Writer {
             symbols: HashMap::new(),
             branch_table: HashMap::new(),
             constants: HashMap::new(),
+            sequences: 0,
+            assets: Vec::new(),
+            targets: Vec::new(),
         }
     }
 
@@ -39,165 +50,359 @@ impl Writer {
         }
     }
 
-    pub fn process_lines(&mut self, input: &Reader) {
-        let mut current_line: usize = 0;
-        let mut last_line_type = &LineType::Undefined;
+    pub fn process_lines(&mut self, input: &Reader) -> Result<(), Vec<Diagnostic>> {
+        let story = &input.story;
 
-        for line in &input.lines {
-            match line.type_ {
-                LineType::Undefined => panic!(format!(
-                    "Line {} cannot be parsed - content: {}",
-                    &current_line, &line.text
-                )),
-                LineType::Text => {
-                    let re_key = Regex::new(r"\{(?P<key>.*?)\}").unwrap();
+        self.constants.clone_from(&story.constants);
 
-                    // If text has variables inside
-                    if re_key.is_match(&line.text) {
-                        // Copy the buffer
-                        let mut output = line.text.clone();
+        self.process_variables(story);
+        self.process_characters(story);
+        self.process_assets(story);
+        self.process_nodes(story, &story.nodes);
 
-                        for caps in re_key.captures_iter(&line.text) {
-                            // I replace the content of the variable
-                            // With the value on the constant table
-                            let key = caps.get(1).unwrap().as_str();
-                            output = output.replace(&format!("{{{}}}", key), &self.constants[key]);
-                        }
+        for knot in &story.knots {
+            self.symbols.insert(knot.name.clone(), self.index);
+            self.process_nodes(story, &knot.nodes);
 
-                        // Push the buffer to the output
-                        self.push_to_output(&format!("P;{}", &output));
-                    } else {
-                        // If has no variables inside
-                        self.push_to_output(&format!("P;{}", line.text));
-                    }
+            for stitch in &knot.stitches {
+                self.symbols.insert(stitch.name.clone(), self.index);
+                self.process_nodes(story, &stitch.nodes);
+            }
+        }
+
+        // The last record has no separator
+        if self.output.ends_with('|') {
+            self.output.pop();
+            self.index -= 1;
+        }
+
+        self.replace_branch_table();
+
+        let diagnostics: Vec<Diagnostic> = self
+            .targets
+            .iter()
+            .filter(|target| !self.symbols.contains_key(&target.name))
+            .map(|target| Diagnostic {
+                file: target.file,
+                ..Diagnostic::error(
+                    ErrorCode::UndefinedBookmark,
+                    target.line,
+                    target.span.clone(),
+                    &format!("Bookmark {} is not defined", target.name),
+                )
+            })
+            .collect();
+
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    fn process_variables(&mut self, story: &Story) {
+        if story.variables.is_empty() {
+            return;
+        }
+
+        // The header declares every variable with its initial value
+        self.push_to_output("V");
+
+        for variable in &story.variables {
+            self.push_to_output(&format!(
+                ";{};{}",
+                escape(&variable.name),
+                escape(&variable.value.to_string())
+            ));
+        }
+
+        self.push_to_output("|");
+    }
+
+    fn process_characters(&mut self, story: &Story) {
+        if story.characters.is_empty() {
+            return;
+        }
+
+        // Dialogue refers to the characters by their position in the table
+        self.push_to_output(&format!("N;{}|", escape_all(&story.characters)));
+    }
+
+    fn process_assets(&mut self, story: &Story) {
+        self.assets = story.assets();
+
+        if !self.assets.is_empty() {
+            self.push_to_output(&format!("R;{}|", escape_all(&self.assets)));
+        }
+    }
+
+    fn process_nodes(&mut self, story: &Story, nodes: &[Node]) {
+        for node in nodes {
+            // Records reached only after taking a choice
+            let mut bodies = Vec::new();
+            let mut gather = None;
+
+            match node {
+                Node::Paragraph(segments) => self.push_paragraph(story, "P;", segments),
+                Node::Dialogue(character, segments) => {
+                    self.push_paragraph(story, &format!("A;{};", character), segments);
                 }
-                LineType::Question => {
-                    // Check between brackets
-                    let re_text = Regex::new(r"\[(.*?)\]")
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get key of question while parsing at line {}",
-                                &current_line
-                            )
-                        });
-
-                    // Check after arrow
-                    let re_jump = Regex::new(r"\->\s+(.*)$")
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get value of question while parsing at line {}",
-                                &current_line
-                            )
-                        });
-
-                    let mut jump_pos_offset = 0;
-
-                    // Q; prefix offset
-                    if last_line_type != &LineType::Question {
-                        self.push_to_output("Q;");
+                Node::Choices(choices) => {
+                    // Choices without a divert join back after the group
+                    let gather_name = format!("__gather_{}", self.index);
+
+                    self.push_to_output("Q;");
+
+                    for (index, choice) in choices.iter().enumerate() {
+                        if index > 0 {
+                            self.push_to_output(";");
+                        }
+
+                        // Once-only choices are marked with an asterisk
+                        if choice.once {
+                            self.push_to_output("*");
+                        }
+
+                        // Conditions are written between square brackets
+                        if let Some(condition) = &choice.condition {
+                            self.push_to_output("[");
+                            self.push_expression(&condition.expression, escape);
+                            self.push_to_output("]");
+                        }
+
+                        // Add question text
+                        self.push_label(story, &choice.label);
+                        self.push_to_output(";");
+
+                        if let Some(target) = &choice.target {
+                            self.targets.push(target.clone());
+                        }
+
+                        let next = choice
+                            .target
+                            .as_ref()
+                            .map_or(&gather_name, |target| &target.name);
+                        let mut body = choice.nodes();
+
+                        // Tags of the choice are emitted once it's taken
+                        if !choice.tags.is_empty() {
+                            body.insert(0, Node::Tags(choice.tags.clone()));
+                        }
+
+                        if body.is_empty() {
+                            // Add jump place
+                            self.push_branch(next);
+                        } else {
+                            // The body is written after the question
+                            let body_name = format!("__choice_{}", self.index);
+                            self.push_branch(&body_name);
+
+                            bodies.push((body_name, body, next.clone()));
+                        }
                     }
 
-                    // Add question text offset
-                    jump_pos_offset += &re_text[1].len() + 1;
-
-                    // Add offset to current index
-                    self.index += jump_pos_offset;
-
-                    // If jump place key is empty, add an empty vector inside
-                    self.branch_table
-                        .entry(re_jump[1].to_string())
-                        .or_insert_with(Vec::new);
-
-                    // Add jump place to that vector
-                    let mut indices = self.branch_table[&re_jump[1].to_string()].clone();
-                    indices.push(self.index);
-
-                    // Add to jump places
-                    self.branch_table.insert(re_jump[1].to_string(), indices);
-
-                    // Add to output (must have 5 numbers)
-                    self.output.push_str(&format!("{};{:05}", &re_text[1], 0));
-
-                    // Jump index offset
-                    self.index += 5;
-                }
-                LineType::Bookmark => {
-                    // Remove equal characters and white spaces
-                    let chars_to_trim: &[char] = &['=', ' '];
-
-                    // Add the new string to the symbols
-                    let trimmed_string: &str = line.text.trim_matches(chars_to_trim);
-
-                    self.symbols.insert(trimmed_string.to_string(), self.index);
-                }
-                LineType::Constant => {
-                    let re_key = Regex::new(r#" ((?:\\.|[^"\\])*) ="#)
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get key while parsing constant at line {}",
-                                &current_line
-                            )
-                        });
-
-                    let re_value = Regex::new(r#""((?:\\.|[^"\\])*)""#)
-                        .unwrap()
-                        .captures(&line.text)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Cannot get value while parsing constant at line {}",
-                                &current_line
-                            )
-                        });
-
-                    // Remove leading and trailing spaces
-                    let const_name = re_key[1].trim().to_string();
-                    let const_value = re_value[1].trim().to_string();
-
-                    // Insert constant to table
-                    self.constants.insert(const_name, const_value);
-                }
-                LineType::Comment => {}
-                LineType::End => {
+                    gather = Some(gather_name);
+                }
+                Node::Jump(target) => {
+                    self.targets.push(target.clone());
+                    self.push_to_output("J;");
+                    self.push_branch(&target.name);
+                }
+                Node::End => {
                     self.push_to_output("E;");
                 }
+                Node::Done => {
+                    self.push_to_output("D;");
+                }
+                Node::Assignment(name, expression) => {
+                    self.push_to_output(&format!("L;{};", name));
+                    self.push_expression(expression, escape);
+                }
+                Node::Tags(tags) => {
+                    self.push_to_output(&format!("T;{}", escape_all(tags)));
+                }
+                Node::Command(command) => self.push_command(command),
+                // Glue without text joins the next paragraph to the current one
+                Node::Glue => {
+                    self.push_to_output("G;");
+                }
             }
 
-            last_line_type = &line.type_;
+            // Records are separated by a pipe
+            self.push_to_output("|");
 
-            current_line += 1;
+            for (body_name, body, next) in bodies {
+                self.symbols.insert(body_name, self.index);
+                self.process_nodes(story, &body);
 
-            // If it's the last line, it exits the function
-            if current_line >= input.lines.len() {
-                return;
+                // Continue to the target or to the gather, unless the body already left
+                if !matches!(body.last(), Some(node) if node.diverts()) {
+                    self.push_to_output("J;");
+                    self.push_branch(&next);
+                    self.push_to_output("|");
+                }
+            }
+
+            if let Some(gather_name) = gather {
+                self.symbols.insert(gather_name, self.index);
             }
+        }
+    }
+
+    // Paragraphs start with their own record, then continue with glue records
+    fn push_paragraph(&mut self, story: &Story, first_record: &str, segments: &[Segment]) {
+        let mut record = first_record.to_string();
 
-            match line.type_ {
-                LineType::Undefined => {
-                    panic!(format!("Line {} cannot be parsed", &current_line - 1))
+        for segment in segments {
+            match segment {
+                // Variables are printed by the runtime, then the text continues the paragraph
+                Segment::Variable(name) => {
+                    self.push_to_output(&format!("{}|I;{}", record, escape(name)));
+                    record = String::from("|G;");
                 }
-                LineType::Text | LineType::End => {
-                    self.push_to_output("|");
+                // The runtime picks one of the texts
+                Segment::Conditional(condition, then, otherwise) => {
+                    self.push_to_output(&format!("{}|", record));
+                    self.push_conditional(condition, then, otherwise, false);
+                    record = String::from("|G;");
                 }
-                LineType::Question => {
-                    if input.lines[current_line].type_ == LineType::Question {
-                        self.push_to_output(";");
-                    } else {
-                        self.push_to_output("|");
-                    }
+                Segment::Sequence(kind, options) => {
+                    self.push_to_output(&format!("{}|", record));
+                    self.push_sequence(*kind, options, false);
+                    record = String::from("|G;");
                 }
-                _ => (),
+                _ => record.push_str(&escape(&story.text(std::slice::from_ref(segment)))),
             }
+        }
 
-            self.replace_branch_table();
+        if record != "|G;" {
+            self.push_to_output(&record);
+        }
+    }
+
+    // Labels are a single field, so what the runtime fills in is written between braces,
+    // like its own record but with the fields escaped once more
+    fn push_label(&mut self, story: &Story, segments: &[Segment]) {
+        // A leading * or [ would be read as a marker
+        if let Some(segment @ (Segment::Text(_) | Segment::Constant(_))) = segments.first() {
+            let text = story.text(std::slice::from_ref(segment));
+            if text.starts_with('*') || text.starts_with('[') {
+                self.push_to_output("\\");
+            }
+        }
+
+        for segment in segments {
+            match segment {
+                Segment::Variable(name) => self.push_to_output(&format!("{{{}}}", escape(name))),
+                Segment::Conditional(condition, then, otherwise) => {
+                    self.push_to_output("{");
+                    self.push_conditional(condition, then, otherwise, true);
+                    self.push_to_output("}");
+                }
+                Segment::Sequence(kind, options) => {
+                    self.push_to_output("{");
+                    self.push_sequence(*kind, options, true);
+                    self.push_to_output("}");
+                }
+                _ => self.push_to_output(&escape(&story.text(std::slice::from_ref(segment)))),
+            }
+        }
+    }
+
+    // Inside a label the fields are escaped once more, and so are the separators
+    fn push_conditional(
+        &mut self,
+        condition: &Condition,
+        then: &str,
+        otherwise: &str,
+        nested: bool,
+    ) {
+        let (separator, escape) = field_escaping(nested);
+
+        self.push_to_output(&format!("C{}", separator));
+        self.push_expression(&condition.expression, escape);
+        self.push_to_output(&format!(
+            "{separator}{}{separator}{}",
+            escape(then),
+            escape(otherwise),
+            separator = separator
+        ));
+    }
+
+    fn push_sequence(&mut self, kind: SequenceKind, options: &[String], nested: bool) {
+        let (separator, escape) = field_escaping(nested);
+        let kind = match kind {
+            SequenceKind::Stopping => "stop",
+            SequenceKind::Cycle => "cycle",
+            SequenceKind::Once => "once",
+            SequenceKind::Shuffle => "shuffle",
+        };
+        let options: Vec<String> = options.iter().map(|option| escape(option)).collect();
+
+        self.push_to_output(&format!(
+            "S{separator}{}{separator}{}{separator}{}",
+            kind,
+            self.sequences,
+            options.join(separator),
+            separator = separator
+        ));
+        self.sequences += 1;
+    }
+
+    fn push_command(&mut self, command: &Command) {
+        let asset = command
+            .asset()
+            .and_then(|asset| self.assets.iter().position(|a| a == asset))
+            .unwrap_or_default();
+
+        let record = match command {
+            Command::Background(_) => format!("B;{}", asset),
+            Command::Show {
+                character,
+                position,
+                ..
+            } => format!(
+                "F;{};{};{}",
+                escape(character),
+                asset,
+                position.as_deref().unwrap_or_default()
+            ),
+            Command::Hide(character) => format!("H;{}", escape(character)),
+            Command::Music(_) => format!("M;{}", asset),
+            Command::Sound(_) => format!("X;{}", asset),
+        };
+
+        self.push_to_output(&record);
+    }
+
+    fn push_expression(&mut self, expression: &Expression, escape: fn(&str) -> String) {
+        for (index, token) in expression.tokens.iter().enumerate() {
+            if index > 0 {
+                self.push_to_output(" ");
+            }
+
+            match token {
+                // Visit counts refer to the jump place of the knot or stitch
+                Token::Visits(name) => {
+                    self.push_to_output("@");
+                    self.push_branch(name);
+                }
+                _ => self.push_to_output(&escape(&token.to_string())),
+            }
         }
     }
 
+    fn push_branch(&mut self, target: &str) {
+        // Add jump place to the branch table, it will be replaced later
+        self.branch_table
+            .entry(target.to_string())
+            .or_default()
+            .push(self.index);
+
+        // Add to output (must have 5 numbers)
+        self.push_to_output(&format!("{:05}", 0));
+    }
+
     fn push_to_output(&mut self, text: &str) {
         // Add processed line to the output
         self.output.push_str(text);
@@ -205,3 +410,73 @@ impl Writer {
         self.index += &text.len();
     }
 }
+
+// Separators and backslashes in the text are preceded by a backslash
+pub fn escape(text: &str) -> String {
+    let mut output = String::new();
+
+    for char_ in text.chars() {
+        if char_ == '|' || char_ == ';' || char_ == '\\' {
+            output.push('\\');
+        }
+        output.push(char_);
+    }
+
+    output
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut output = String::new();
+    let mut chars = text.chars();
+
+    while let Some(char_) = chars.next() {
+        if char_ == '\\' {
+            output.extend(chars.next());
+        } else {
+            output.push(char_);
+        }
+    }
+
+    output
+}
+
+// Splits records on '|' and fields on ';', skipping the escaped ones.
+// The parts are still escaped, so that markers can be told apart from text
+pub fn split_escaped(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (position, char_) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if char_ == '\\' {
+            escaped = true;
+        } else if char_ == separator {
+            parts.push(&text[start..position]);
+            start = position + 1;
+        }
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+// Fields inside a label are escaped once for themselves and once for the label,
+// like the separators between them
+fn field_escaping(nested: bool) -> (&'static str, fn(&str) -> String) {
+    if nested {
+        ("\\;", escape_twice)
+    } else {
+        (";", escape)
+    }
+}
+
+fn escape_twice(text: &str) -> String {
+    escape(&escape(text))
+}
+
+fn escape_all(texts: &[String]) -> String {
+    let texts: Vec<String> = texts.iter().map(|text| escape(text)).collect();
+    texts.join(";")
+}
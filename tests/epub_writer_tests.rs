@@ -2,20 +2,22 @@ extern crate stevia;
 #[macro_use]
 extern crate maplit;
 
+use stevia::diagnostic::*;
 use stevia::epub_writer::*;
 use stevia::reader::*;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[allow(unused_macros)]
 macro_rules! SETUP_WRITER {
     ($input:expr, $reader:ident, $writer:ident) => {
         let input = $input;
         let mut $reader = Reader::from_text(input);
-        $reader.parse_all_lines();
+        $reader.parse_all_lines().unwrap();
 
         let mut $writer =
             EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
-        $writer.process_lines(&$reader);
+        $writer.process_lines(&$reader).unwrap();
     };
 }
 
@@ -68,6 +70,18 @@ Bonjour monde"#,
     assert_eq!(reader.lines[2].type_, LineType::Text);
 }
 
+#[test]
+fn test_writer_glue() {
+    SETUP_WRITER!(
+        "Hello <>
+world",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p>Hello world</p>"]);
+}
+
 // --- QUESTIONS ---
 
 #[test]
@@ -97,13 +111,186 @@ Ciao mondo
     assert_eq!(reader.lines[5].type_, LineType::Text);
 }
 
+#[test]
+fn test_writer_question_output() {
+    SETUP_WRITER!(
+        "+ [Ask] \"Where is it?\" -> example
++ [Leave] -> example
+=== example
+Hello world
+",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p><a href=\"chapter_2.xhtml\">Ask</a></p><p><a href=\"chapter_1.xhtml\">Leave</a></p>", "<p>Hello world</p>", "<p>\"Where is it?\"</p><p>Hello world</p>"]);
+}
+
+#[test]
+fn test_writer_question_output_same_target() {
+    SETUP_WRITER!(
+        "+ [Ask] Where is it? -> example
++ [Shout] Give it back! -> example
+=== example
+Hello world
+-> END
+",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p><a href=\"chapter_2.xhtml\">Ask</a></p><p><a href=\"chapter_3.xhtml\">Shout</a></p>",
+            "<p>Hello world</p><p class=\"end\">The End</p>",
+            "<p>Where is it?</p><p>Hello world</p><p class=\"end\">The End</p>",
+            "<p>Give it back!</p><p>Hello world</p><p class=\"end\">The End</p>"
+        ]
+    );
+}
+
+#[test]
+fn test_writer_question_undefined_bookmark() {
+    let mut reader = Reader::from_text(
+        "Hello world
+
+  + [Hello world] -> example",
+    );
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
+    let diagnostics = writer.process_lines(&reader).unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedBookmark,
+            3,
+            21..28,
+            "Bookmark example is not defined"
+        )]
+    );
+}
+
+// --- GATHERS ---
+
+#[test]
+fn test_writer_gather_one() {
+    SETUP_WRITER!(
+        "What do you ask?
++ [Where?]
+  Over there.
++ [When?] -> later
+- They left.
+== later
+Now",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p>What do you ask?</p><p><a href=\"chapter_3.xhtml\">Where?</a></p><p><a href=\"chapter_1.xhtml\">When?</a></p>", "<p>Now</p>", "<p>They left.</p>", "<p>Over there.</p><p><a href=\"chapter_2.xhtml\">Continue</a></p>"]);
+}
+
+#[test]
+fn test_writer_gather_nested_choices() {
+    SETUP_WRITER!(
+        "+ [A]
+  + + [A1]
+    Deep
+  + + [A2]
+  - - Back
++ [B]
+- End",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p><a href=\"chapter_2.xhtml\">A</a></p><p><a href=\"chapter_1.xhtml\">B</a></p>", "<p>End</p>", "<p><a href=\"chapter_4.xhtml\">A1</a></p><p><a href=\"chapter_3.xhtml\">A2</a></p>", "<p>Back</p><p><a href=\"chapter_1.xhtml\">Continue</a></p>", "<p>Deep</p><p><a href=\"chapter_3.xhtml\">Continue</a></p>"]);
+}
+
+// --- JUMPS ---
+
+#[test]
+fn test_writer_jump_one() {
+    SETUP_WRITER!(
+        "Hello world
+-> example
+=== example
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p>Hello world</p><p><a href=\"chapter_1.xhtml\">Continue</a></p>",
+            "<p>Ciao mondo</p>"
+        ]
+    );
+
+    assert_eq!(reader.lines[1].type_, LineType::Jump);
+}
+
+#[test]
+fn test_writer_jump_undefined_bookmark() {
+    let mut reader = Reader::from_text("->  example ");
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
+    let diagnostics = writer.process_lines(&reader).unwrap_err();
+
+    assert_eq!(diagnostics[0].code, ErrorCode::UndefinedBookmark);
+    assert_eq!(diagnostics[0].span, 4..11);
+}
+
+// --- STITCHES ---
+
+#[test]
+fn test_writer_stitch_one() {
+    SETUP_WRITER!(
+        "+ [Hello world] -> hello.world
+=== hello
+-> world
+= world
+Hello world
+=== ciao
+= world
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p><a href=\"chapter_2.xhtml\">Hello world</a></p>",
+            "<p><a href=\"chapter_2.xhtml\">Continue</a></p>",
+            "<p>Hello world</p>",
+            "",
+            "<p>Ciao mondo</p>"
+        ]
+    );
+
+    assert_eq!(
+        writer.bookmark_table,
+        hashmap! {
+            String::from("hello") => 1 as usize,
+            String::from("hello.world") => 2 as usize,
+            String::from("ciao") => 3 as usize,
+            String::from("ciao.world") => 4 as usize,
+        }
+    );
+}
+
 // --- END ---
 
 #[test]
 fn test_writer_end_one() {
     SETUP_WRITER!("-> END", reader, writer);
 
-    assert_eq!(writer.page_content, vec![""]);
+    assert_eq!(writer.page_content, vec!["<p class=\"end\">The End</p>"]);
 
     assert_eq!(reader.lines[0].type_, LineType::End);
 }
@@ -117,16 +304,257 @@ fn test_writer_end_two() {
         writer
     );
 
-    assert_eq!(writer.page_content, vec!["<p>Hello world</p>"]);
+    assert_eq!(
+        writer.page_content,
+        vec!["<p>Hello world</p><p class=\"end\">The End</p>"]
+    );
 
     assert_eq!(reader.lines[0].type_, LineType::Text);
     assert_eq!(reader.lines[1].type_, LineType::End);
 }
 
+#[test]
+fn test_writer_end_in_choices() {
+    SETUP_WRITER!(
+        "+ [Quit] -> END
++ [Pause] -> DONE
++ [Stay] -> stay
+== stay
+Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p><a href=\"chapter_2.xhtml\">Quit</a></p><p><a href=\"chapter_3.xhtml\">Pause</a></p><p><a href=\"chapter_1.xhtml\">Stay</a></p>",
+            "<p>Hello</p>",
+            "<p class=\"end\">The End</p>",
+            "<p class=\"done\">To be continued</p>",
+        ]
+    );
+}
+
+#[test]
+fn test_writer_end_after_text() {
+    SETUP_WRITER!(
+        "+ [Quit] Bye -> END
++ [Stay] -> stay
+== stay
+Hello
+-> DONE",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p><a href=\"chapter_2.xhtml\">Quit</a></p><p><a href=\"chapter_1.xhtml\">Stay</a></p>",
+            "<p>Hello</p><p class=\"done\">To be continued</p>",
+            "<p>Bye</p><p class=\"end\">The End</p>",
+        ]
+    );
+}
+
 // --- BOOKMARKS ---
 
 // --- CONSTANTS ---
 
+// --- VARIABLES ---
+
+#[test]
+fn test_writer_variable_initial_value() {
+    SETUP_WRITER!(
+        r#"VAR name = "Bob"
+~ name = "Alice"
+Hello {name}"#,
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p>Hello Bob</p>"]);
+}
+
+// --- CONDITIONS ---
+
+#[test]
+fn test_writer_conditional_choice() {
+    SETUP_WRITER!(
+        "VAR met_anna = false
++ {met_anna} [Ask about Anna] -> anna
+== anna
+Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content[0],
+        "<p><a href=\"chapter_1.xhtml\">Ask about Anna</a> (if met anna)</p>"
+    );
+}
+
+#[test]
+fn test_writer_inline_conditional() {
+    SETUP_WRITER!(
+        "VAR tired = false
+You feel {tired: exhausted|fine}.",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.page_content, vec!["<p>You feel fine.</p>"]);
+}
+
+// --- SEQUENCES ---
+
+#[test]
+fn test_writer_sequence_first_text() {
+    SETUP_WRITER!("Today is {&Monday|Tuesday}. {!Hello}", reader, writer);
+
+    assert_eq!(writer.page_content, vec!["<p>Today is Monday. Hello</p>"]);
+}
+
+// --- TAGS ---
+
+#[test]
+fn test_writer_tags() {
+    SETUP_WRITER!(
+        "# bg: Dark forest
+Hello # loud
+Bye",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p class=\"tag-bg-dark-forest tag-loud\" data-bg=\"Dark forest\">Hello</p><p>Bye</p>"
+        ]
+    );
+}
+
+#[test]
+fn test_writer_tags_choice() {
+    SETUP_WRITER!("+ [Yes] -> yes # happy\n== yes", reader, writer);
+
+    assert_eq!(
+        writer.page_content[0],
+        "<p class=\"tag-happy\"><a href=\"chapter_1.xhtml\">Yes</a></p>"
+    );
+}
+
+#[test]
+fn test_writer_tags_before_choices() {
+    SETUP_WRITER!("Hi\n# mood: sad\n+ [A] -> k # happy\n== k", reader, writer);
+
+    assert_eq!(
+        writer.page_content[0],
+        "<p>Hi</p><p class=\"tag-mood-sad tag-happy\" data-mood=\"sad\"><a href=\"chapter_1.xhtml\">A</a></p>"
+    );
+}
+
+#[test]
+fn test_writer_tags_before_jump_and_end() {
+    SETUP_WRITER!("Hi\n# loud\n-> k\n== k\n# sad\n-> END", reader, writer);
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p>Hi</p><p class=\"tag-loud\"><a href=\"chapter_1.xhtml\">Continue</a></p>",
+            "<p class=\"end tag-sad\">The End</p>"
+        ]
+    );
+}
+
+// --- DIALOGUE ---
+
+#[test]
+fn test_writer_dialogue() {
+    SETUP_WRITER!(
+        "CHARACTER Anna
+Anna: Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec!["<p><span class=\"speaker\">Anna</span> Hello</p>"]
+    );
+}
+
+// --- COMMANDS ---
+
+#[test]
+fn test_writer_commands() {
+    // Backgrounds are next to the story file
+    let directory = std::env::temp_dir().join("stevia_epub_commands");
+    fs::create_dir_all(&directory).unwrap();
+    fs::copy("examples/cover.jpg", directory.join("forest.jpg")).unwrap();
+    fs::write(
+        directory.join("story.ink"),
+        "@bg forest
+@music theme
+Hello
+@bg forest",
+    )
+    .unwrap();
+
+    let mut reader = Reader::from_file(&directory.join("story.ink")).unwrap();
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
+    writer.process_lines(&reader).unwrap();
+
+    assert_eq!(
+        writer.page_content,
+        vec!["<img class=\"background\" src=\"forest.jpg\" alt=\"forest\"/><p>Hello</p><img class=\"background\" src=\"forest.jpg\" alt=\"forest\"/>"]
+    );
+    assert_eq!(writer.backgrounds, vec!["forest"]);
+    assert_eq!(writer.background_images.len(), 1);
+    assert_eq!(writer.background_images[0].0, "forest");
+
+    assert!(writer.generate().is_some());
+}
+
+#[test]
+fn test_writer_commands_asset_directory() {
+    let mut reader = Reader::from_text("@bg cover\nHello");
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
+    writer.asset_directory = Some(PathBuf::from("examples"));
+    writer.process_lines(&reader).unwrap();
+
+    assert_eq!(writer.background_images[0].0, "cover");
+
+    assert!(writer.generate().is_some());
+}
+
+#[test]
+fn test_writer_commands_missing_background() {
+    let mut reader = Reader::from_text("Hello\n@bg  nowhere\n@bg nowhere");
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = EpubWriter::new("I love Rust", "Pomettini", Path::new("examples/cover.jpg"));
+    let diagnostics = writer.process_lines(&reader).unwrap_err();
+
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].code, ErrorCode::MissingAsset);
+    assert_eq!(diagnostics[0].line, 2);
+    assert_eq!(diagnostics[0].span, 5..12);
+    assert_eq!(diagnostics[1].line, 3);
+    assert_eq!(diagnostics[1].span, 4..11);
+    assert_eq!(
+        diagnostics[0].message,
+        "Cannot find background nowhere.jpg, .jpeg or .png"
+    );
+}
+
 // --- COMMENTS ---
 
 #[test]
@@ -170,6 +598,25 @@ Bonjour monde",
     assert_eq!(reader.lines[2].type_, LineType::Text);
 }
 
+#[test]
+fn test_writer_trailing_comment() {
+    SETUP_WRITER!(
+        "Hello world // TODO rewrite
++ [Hello world] -> example // TODO rename
+=== example // First chapter",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.page_content,
+        vec![
+            "<p>Hello world</p><p><a href=\"chapter_1.xhtml\">Hello world</a></p>",
+            ""
+        ]
+    );
+}
+
 // --- FUNCTIONAL TESTS ---
 
 #[test]
@@ -188,7 +635,7 @@ Do you like it?
 
     assert_eq!(
         writer.page_content,
-        vec!["<p>Hello there</p><p>I\'m a VN written in the Ink format</p><p>Do you like it?</p>"]
+        vec!["<p>Hello there</p><p>I\'m a VN written in the Ink format</p><p>Do you like it?</p><p class=\"end\">The End</p>"]
     );
 
     assert_eq!(reader.lines[0].type_, LineType::Text);
@@ -226,7 +673,7 @@ Oh, I see
 
     assert_eq!(
         writer.page_content,
-        vec!["<p>Hello there</p><p>I\'m a VN written in the Ink format</p><p>Do you like it?</p><p><a href=\"chapter_1.xhtml\">Yes, I like it!</a></p><p><a href=\"chapter_2.xhtml\">No, I do not like it</a></p>", "<p>Thank you!</p>", "<p>Oh, I see</p>"]
+        vec!["<p>Hello there</p><p>I\'m a VN written in the Ink format</p><p>Do you like it?</p><p><a href=\"chapter_1.xhtml\">Yes, I like it!</a></p><p><a href=\"chapter_2.xhtml\">No, I do not like it</a></p>", "<p>Thank you!</p><p class=\"end\">The End</p>", "<p>Oh, I see</p><p class=\"end\">The End</p>"]
     );
 
     assert_eq!(
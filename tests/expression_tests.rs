@@ -0,0 +1,206 @@
+extern crate stevia;
+
+use stevia::expression::*;
+
+#[allow(unused_macros)]
+macro_rules! SETUP_EXPRESSION {
+    ($expression:ident, $input:expr) => {
+        let input = $input;
+        let $expression = Expression::parse(input).unwrap();
+    };
+}
+
+#[test]
+fn test_expression_number() {
+    SETUP_EXPRESSION!(expression, "42");
+
+    assert_eq!(expression.tokens, vec![Token::Number(42)]);
+}
+
+#[test]
+fn test_expression_literals() {
+    SETUP_EXPRESSION!(expression, r#""Hello \"world\"" == true"#);
+
+    assert_eq!(
+        expression.tokens,
+        vec![
+            Token::Text(r#"Hello "world""#.to_string()),
+            Token::Boolean(true),
+            Token::Operator("==")
+        ]
+    );
+    assert_eq!(expression.to_string(), r#""Hello \"world\"" true =="#);
+}
+
+#[test]
+fn test_expression_variable() {
+    SETUP_EXPRESSION!(expression, "trust + 1");
+
+    assert_eq!(expression.to_string(), "trust 1 +");
+    assert_eq!(expression.variables(), vec!["trust"]);
+}
+
+#[test]
+fn test_expression_precedence() {
+    SETUP_EXPRESSION!(expression, "1 + 2 * 3");
+
+    assert_eq!(expression.to_string(), "1 2 3 * +");
+}
+
+#[test]
+fn test_expression_parenthesis() {
+    SETUP_EXPRESSION!(expression, "(1 + 2) * 3");
+
+    assert_eq!(expression.to_string(), "1 2 + 3 *");
+}
+
+#[test]
+fn test_expression_left_to_right() {
+    SETUP_EXPRESSION!(expression, "a - b - c");
+
+    assert_eq!(expression.to_string(), "a b - c -");
+}
+
+#[test]
+fn test_expression_unary() {
+    SETUP_EXPRESSION!(expression, "-x + 1");
+
+    assert_eq!(expression.to_string(), "x neg 1 +");
+}
+
+#[test]
+fn test_expression_logic() {
+    SETUP_EXPRESSION!(expression, "not a && b || c");
+
+    assert_eq!(expression.to_string(), "a not b and c or");
+}
+
+#[test]
+fn test_expression_comparison() {
+    SETUP_EXPRESSION!(expression, "x >= 10 and y mod 2 != 0");
+
+    assert_eq!(expression.to_string(), "x 10 >= y 2 % 0 != and");
+}
+
+#[test]
+fn test_expression_incomplete() {
+    assert_eq!(
+        Expression::parse("1 +").unwrap_err(),
+        "Expression is not complete"
+    );
+    assert_eq!(
+        Expression::parse("1 2").unwrap_err(),
+        "Expression is not complete"
+    );
+}
+
+#[test]
+fn test_expression_parenthesis_not_closed() {
+    assert_eq!(
+        Expression::parse("(1 + 2").unwrap_err(),
+        "Parenthesis is never closed"
+    );
+    assert_eq!(
+        Expression::parse("1 + 2)").unwrap_err(),
+        "Unexpected closing parenthesis"
+    );
+}
+
+#[test]
+fn test_expression_unexpected_character() {
+    assert_eq!(
+        Expression::parse("1 + $").unwrap_err(),
+        "Unexpected character $"
+    );
+}
+
+#[test]
+fn test_expression_literal_values() {
+    assert_eq!(Token::literal("-3"), Some(Token::Number(-3)));
+    assert_eq!(Token::literal("false"), Some(Token::Boolean(false)));
+    assert_eq!(
+        Token::literal(r#""Bob""#),
+        Some(Token::Text("Bob".to_string()))
+    );
+    assert_eq!(Token::literal("Bob"), None);
+}
+
+#[test]
+fn test_expression_evaluate() {
+    SETUP_EXPRESSION!(expression, "trust * 2 + 1 > 6 and not tired");
+
+    let value = expression.evaluate(|token| match token {
+        Token::Variable(name) if name == "trust" => Some(Token::Number(3)),
+        Token::Variable(name) if name == "tired" => Some(Token::Boolean(false)),
+        _ => None,
+    });
+
+    assert_eq!(value, Ok(Token::Boolean(true)));
+}
+
+#[test]
+fn test_expression_evaluate_text() {
+    SETUP_EXPRESSION!(expression, r#""Level " + 2"#);
+
+    assert_eq!(
+        expression.evaluate(|_| None),
+        Ok(Token::Text("Level 2".to_string()))
+    );
+}
+
+#[test]
+fn test_expression_evaluate_errors() {
+    SETUP_EXPRESSION!(division, "1 / 0");
+    SETUP_EXPRESSION!(unknown, "trust + 1");
+    SETUP_EXPRESSION!(mismatch, "true - 1");
+
+    assert_eq!(
+        division.evaluate(|_| None),
+        Err("Division by zero".to_string())
+    );
+    assert_eq!(
+        unknown.evaluate(|_| None),
+        Err("trust has no value".to_string())
+    );
+    assert_eq!(
+        mismatch.evaluate(|_| None),
+        Err("Cannot apply - to true and 1".to_string())
+    );
+}
+
+#[test]
+fn test_expression_from_rpn() {
+    SETUP_EXPRESSION!(
+        expression,
+        r#"-trust * 2 mod 3 == "a \"b\" c" or not tired"#
+    );
+
+    assert_eq!(
+        Expression::from_rpn(&expression.to_string()),
+        Ok(expression)
+    );
+    assert_eq!(
+        Expression::from_rpn("@00042 1 >").unwrap().tokens,
+        vec![
+            Token::Visits("00042".to_string()),
+            Token::Number(1),
+            Token::Operator(">")
+        ]
+    );
+}
+
+#[test]
+fn test_expression_from_rpn_errors() {
+    assert_eq!(
+        Expression::from_rpn("1 +"),
+        Err("Expression is not complete".to_string())
+    );
+    assert_eq!(
+        Expression::from_rpn("\"open"),
+        Err("String is never closed".to_string())
+    );
+    assert_eq!(
+        Expression::from_rpn("1 2 $"),
+        Err("Unexpected token $".to_string())
+    );
+}
@@ -137,6 +137,38 @@ fn test_stevia_functional_correct() {
     clean();
 }
 
+#[test]
+fn test_stevia_functional_diagnostics() {
+    SETUP_UI_MULTILINE!(ui, log_ctx, multiline_entry);
+
+    write("examples/broken.ink", "Hello {WORLD}").unwrap();
+
+    let state = State {
+        input_file: Some(PathBuf::from(r"examples/broken.ink")),
+        output_file: Some(PathBuf::from(r"examples/broken.stevia")),
+        export_format: Some(ExportFormat::Stevia),
+        title: String::from("Hello world"),
+        author: String::from("Pomettini"),
+        cover: None,
+    };
+
+    let result = process(&mut log_ctx, &state);
+
+    let value = multiline_entry.value(&ui);
+
+    FREE!(multiline_entry);
+
+    remove_file("examples/broken.ink").unwrap();
+
+    assert_eq!(result, Err(()));
+    assert!(value.contains(
+        "examples/broken.ink: error[E0004] at line 1, columns 7-13: Constant WORLD is not defined"
+    ));
+    assert_eq!(Path::new("examples/broken.stevia").exists(), false);
+
+    clean();
+}
+
 #[test]
 fn test_stevia_functional_no_input_file() {
     SETUP_UI_MULTILINE!(ui, log_ctx, multiline_entry);
@@ -1,5 +1,6 @@
 extern crate stevia;
 
+use stevia::diagnostic::*;
 use stevia::reader::*;
 
 #[allow(unused_macros)]
@@ -7,7 +8,7 @@ macro_rules! SETUP_READER {
     ($reader:ident, $input:expr) => {
         let input = $input;
         let mut $reader = Reader::from_text(input);
-        $reader.parse_all_lines();
+        $reader.parse_all_lines().unwrap();
     };
 }
 
@@ -180,6 +181,27 @@ fn test_parse_question_one_space() {
     assert_eq!(reader.lines[0].type_, LineType::Question);
 }
 
+#[test]
+fn test_parse_question_once_only() {
+    SETUP_READER!(reader, r#"* [Hello!] -> hello"#);
+
+    assert_eq!(reader.lines.len(), 1);
+    assert_eq!(reader.lines[0].type_, LineType::Question);
+}
+
+#[test]
+fn test_parse_question_nested() {
+    SETUP_READER!(
+        reader,
+        r#"+ [Hello!]
+    + + [World!] -> world
+    * * * [Mondo!] -> mondo"#
+    );
+
+    assert_eq!(reader.lines[1].type_, LineType::Question);
+    assert_eq!(reader.lines[2].type_, LineType::Question);
+}
+
 #[test]
 fn test_parse_question_two() {
     SETUP_READER!(
@@ -226,6 +248,45 @@ fn test_parse_bookmark_two() {
     assert_eq!(reader.lines[1].type_, LineType::Bookmark);
 }
 
+#[test]
+fn test_parse_bookmark_two_equals() {
+    SETUP_READER!(reader, r#"== hello =="#);
+
+    assert_eq!(reader.lines[0].type_, LineType::Bookmark);
+    assert_eq!(reader.lines[0].bookmark_name(), "hello");
+}
+
+#[test]
+fn test_parse_stitch_one() {
+    SETUP_READER!(
+        reader,
+        r#"=== hello
+= world"#
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Bookmark);
+    assert_eq!(reader.lines[1].type_, LineType::Stitch);
+    assert_eq!(reader.lines[1].bookmark_name(), "world");
+}
+
+#[test]
+fn test_parse_stitch_bookmarks() {
+    SETUP_READER!(
+        reader,
+        r#"= intro
+=== hello
+= world
+= mondo
+=== ciao
+= world"#
+    );
+
+    assert_eq!(
+        reader.bookmarks(),
+        vec!["intro", "hello", "hello.world", "hello.mondo", "ciao", "ciao.world"]
+    );
+}
+
 #[test]
 fn test_parse_comment_one() {
     SETUP_READER!(reader, "// Hello world");
@@ -277,6 +338,235 @@ Ciao mondo"
     assert_eq!(reader.lines[1].type_, LineType::Text);
 }
 
+#[test]
+fn test_parse_trailing_comment_text() {
+    SETUP_READER!(reader, "Hello there // TODO rewrite");
+
+    assert_eq!(reader.lines[0].text, "Hello there");
+    assert_eq!(reader.lines[0].span, 0..11);
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_trailing_comment_question() {
+    SETUP_READER!(reader, "+ [Hello world] -> example // TODO rename");
+
+    assert_eq!(reader.lines[0].text, "+ [Hello world] -> example");
+    assert_eq!(reader.lines[0].type_, LineType::Question);
+}
+
+#[test]
+fn test_parse_trailing_comment_bookmark() {
+    SETUP_READER!(reader, "=== example // First chapter");
+
+    assert_eq!(reader.lines[0].bookmark_name(), "example");
+    assert_eq!(reader.lines[0].type_, LineType::Bookmark);
+}
+
+#[test]
+fn test_parse_trailing_comment_jump() {
+    SETUP_READER!(reader, "-> example // Skip the intro");
+
+    assert_eq!(reader.lines[0].type_, LineType::Jump);
+}
+
+#[test]
+fn test_parse_trailing_comment_inside_quotes() {
+    SETUP_READER!(reader, r#"He said "see http://example.com" // Link"#);
+
+    assert_eq!(reader.lines[0].text, r#"He said "see http://example.com""#);
+}
+
+#[test]
+fn test_parse_trailing_comment_inside_braces() {
+    let mut reader = Reader::from_text("Visit {SITE//URL} // Link");
+    let _ = reader.parse_all_lines();
+
+    assert_eq!(reader.lines[0].text, "Visit {SITE//URL}");
+}
+
+#[test]
+fn test_parse_tags_trailing() {
+    SETUP_READER!(reader, "Hello there # mood: happy #loud // TODO");
+
+    assert_eq!(reader.lines[0].text, "Hello there");
+    assert_eq!(reader.lines[0].span, 0..11);
+    assert_eq!(reader.lines[0].tags, vec!["mood: happy", "loud"]);
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_tags_line() {
+    SETUP_READER!(reader, "# bg: forest");
+
+    assert_eq!(reader.lines[0].tags, vec!["bg: forest"]);
+    assert_eq!(reader.lines[0].type_, LineType::Tag);
+}
+
+#[test]
+fn test_parse_tags_question() {
+    SETUP_READER!(reader, "+ [Hello world] -> example # happy\n== example");
+
+    assert_eq!(reader.lines[0].text, "+ [Hello world] -> example");
+    assert_eq!(reader.lines[0].tags, vec!["happy"]);
+}
+
+#[test]
+fn test_parse_tags_inside_quotes() {
+    SETUP_READER!(reader, r##"He said "#1" # quote"##);
+
+    assert_eq!(reader.lines[0].text, r##"He said "#1""##);
+    assert_eq!(reader.lines[0].tags, vec!["quote"]);
+}
+
+#[test]
+fn test_parse_include() {
+    let directory = std::env::temp_dir().join("stevia_parse_include");
+    std::fs::create_dir_all(directory.join("chapters")).unwrap();
+    std::fs::write(directory.join("main.ink"), "Hello\nINCLUDE chapters/one.ink\nBye").unwrap();
+    std::fs::write(directory.join("chapters/one.ink"), "== one\nCiao").unwrap();
+
+    let mut reader = Reader::from_file(&directory.join("main.ink")).unwrap();
+    reader.parse_all_lines().unwrap();
+
+    let texts: Vec<&str> = reader.lines.iter().map(|line| line.text.as_str()).collect();
+    let files: Vec<usize> = reader.lines.iter().map(|line| line.file).collect();
+
+    assert_eq!(texts, vec!["Hello", "== one", "Ciao", "Bye"]);
+    assert_eq!(files, vec![0, 1, 1, 0]);
+    assert_eq!(reader.lines[2].line_number, 2);
+    assert_eq!(reader.files[1], directory.join("chapters/one.ink"));
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_parse_block_comment_one() {
+    SETUP_READER!(reader, "/* Hello world */");
+
+    assert_eq!(reader.lines.len(), 0);
+}
+
+#[test]
+fn test_parse_block_comment_multiple_lines() {
+    SETUP_READER!(
+        reader,
+        "Hello world
+/* Ciao mondo
+
++ [Hello world] -> example
+*/
+Bonjour monde"
+    );
+
+    assert_eq!(reader.lines.len(), 2);
+
+    assert_eq!(reader.lines[0].text, "Hello world");
+    assert_eq!(reader.lines[1].text, "Bonjour monde");
+
+    assert_eq!(reader.lines[1].line_number, 6);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_block_comment_mid_line() {
+    SETUP_READER!(reader, "Hello /* Ciao mondo */ world");
+
+    assert_eq!(reader.lines[0].text, "Hello world");
+    assert_eq!(reader.lines[0].span, 0..28);
+}
+
+#[test]
+fn test_parse_block_comment_start_and_end_of_line() {
+    SETUP_READER!(
+        reader,
+        "Hello /* Ciao
+mondo */ world"
+    );
+
+    assert_eq!(reader.lines.len(), 2);
+
+    assert_eq!(reader.lines[0].text, "Hello");
+    assert_eq!(reader.lines[1].text, "world");
+
+    assert_eq!(reader.lines[1].indentation, 9);
+    assert_eq!(reader.lines[1].span, 23..28);
+}
+
+#[test]
+fn test_parse_block_comment_inside_line_comment() {
+    SETUP_READER!(
+        reader,
+        "// Hello /* world
+Ciao mondo"
+    );
+
+    assert_eq!(reader.lines.len(), 2);
+
+    assert_eq!(reader.lines[0].type_, LineType::Comment);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_block_comment_after_quotes() {
+    SETUP_READER!(
+        reader,
+        r#""a // b" /* c */ ok
+"x // y" /* start
+secret
+*/ after"#
+    );
+
+    assert_eq!(reader.lines.len(), 3);
+
+    assert_eq!(reader.lines[0].text, r#""a // b" ok"#);
+    assert_eq!(reader.lines[1].text, r#""x // y""#);
+    assert_eq!(reader.lines[2].text, "after");
+    assert_eq!(reader.lines[2].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_block_comment_inside_quotes() {
+    SETUP_READER!(reader, r#"He said "/* not a comment */" /* comment */"#);
+
+    assert_eq!(reader.lines[0].text, r#"He said "/* not a comment */""#);
+}
+
+#[test]
+fn test_parse_diagnostic_after_block_comment() {
+    let mut reader = Reader::from_text("  Hello /* mid */ there {UNDEF}");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(reader.lines[0].text, "Hello there {UNDEF}");
+    assert_eq!(diagnostics[0].code, ErrorCode::UndefinedConstant);
+    assert_eq!(diagnostics[0].span, 24..31);
+}
+
+#[test]
+fn test_parse_block_comment_unterminated() {
+    let mut reader = Reader::from_text(
+        "Hello world
+  Ciao /* mondo
+Bonjour monde",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UnterminatedComment,
+            2,
+            7..9,
+            "Block comment is never closed"
+        )]
+    );
+
+    assert_eq!(reader.lines.len(), 2);
+    assert_eq!(reader.lines[1].text, "Ciao");
+}
+
 #[test]
 fn test_parse_end_one() {
     SETUP_READER!(reader, r#"-> END"#);
@@ -309,6 +599,98 @@ fn test_parse_end_two() {
     assert_eq!(reader.lines[1].type_, LineType::End);
 }
 
+#[test]
+fn test_parse_end_prefix_of_knot() {
+    SETUP_READER!(
+        reader,
+        r#"-> ENDING
+-> END_chapter
+== ENDING
+Bye"#
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Jump);
+    assert_eq!(reader.lines[1].type_, LineType::Jump);
+}
+
+#[test]
+fn test_parse_done() {
+    SETUP_READER!(
+        reader,
+        r#"Hello
+->  DONE"#
+    );
+
+    assert_eq!(reader.lines[1].type_, LineType::Done);
+}
+
+#[test]
+fn test_parse_jump_one() {
+    SETUP_READER!(reader, r#"-> hello"#);
+
+    assert_eq!(reader.lines.len(), 1);
+
+    assert_eq!(reader.lines[0].type_, LineType::Jump);
+}
+
+#[test]
+fn test_parse_jump_one_space() {
+    SETUP_READER!(reader, r#" ->   hello "#);
+
+    assert_eq!(reader.lines.len(), 1);
+
+    assert_eq!(reader.lines[0].type_, LineType::Jump);
+}
+
+#[test]
+fn test_parse_jump_and_end() {
+    SETUP_READER!(
+        reader,
+        r#"-> hello
+-> END"#
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Jump);
+    assert_eq!(reader.lines[1].type_, LineType::End);
+}
+
+#[test]
+fn test_parse_jump_with_text() {
+    SETUP_READER!(reader, r#"-> hello world"#);
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_gather() {
+    SETUP_READER!(
+        reader,
+        "- They left.
+-
+- -> END
+-----"
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Gather);
+    assert_eq!(reader.lines[1].type_, LineType::Gather);
+    assert_eq!(reader.lines[2].type_, LineType::Gather);
+    assert_eq!(reader.lines[3].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_variable() {
+    SETUP_READER!(
+        reader,
+        "VAR trust = 0
+~ trust = trust + 1
+VARIABLE"
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Variable);
+    assert_eq!(reader.lines[1].type_, LineType::Logic);
+    assert_eq!(reader.lines[2].type_, LineType::Text);
+}
+
 #[test]
 fn test_parse_text_quotation_marks() {
     SETUP_READER!(
@@ -355,4 +737,445 @@ fn test_parse_text_dash() {
     assert_eq!(reader.lines[0].text, r#"-----"#);
 
     assert_eq!(reader.lines[0].type_, LineType::Text);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_undefined_line() {
+    let mut reader = Reader::from_text(
+        "Hello world
+\u{7}Ciao mondo",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedLine,
+            2,
+            0..11,
+            "Line cannot be parsed"
+        )]
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+    assert_eq!(reader.lines[1].type_, LineType::Undefined);
+}
+
+#[test]
+fn test_parse_line_numbers() {
+    SETUP_READER!(
+        reader,
+        "Hello world
+
+   Ciao mondo
+\t
+Bonjour monde"
+    );
+
+    assert_eq!(reader.lines.len(), 3);
+
+    assert_eq!(reader.lines[0].line_number, 1);
+    assert_eq!(reader.lines[1].line_number, 3);
+    assert_eq!(reader.lines[2].line_number, 5);
+}
+
+#[test]
+fn test_parse_line_spans() {
+    SETUP_READER!(
+        reader,
+        "Hello world
+
+   Ciao mondo"
+    );
+
+    assert_eq!(reader.lines[0].span, 0..11);
+    assert_eq!(reader.lines[0].indentation, 0);
+
+    assert_eq!(reader.lines[1].span, 16..26);
+    assert_eq!(reader.lines[1].indentation, 3);
+
+    assert_eq!(&reader.source[reader.lines[1].span.clone()], "Ciao mondo");
+}
+
+#[test]
+fn test_parse_line_spans_windows_line_endings() {
+    SETUP_READER!(reader, "Hello world\r\n\r\n  Ciao mondo\r\n");
+
+    assert_eq!(reader.lines.len(), 2);
+
+    assert_eq!(reader.lines[0].text, "Hello world");
+    assert_eq!(reader.lines[1].text, "Ciao mondo");
+
+    assert_eq!(reader.lines[1].line_number, 3);
+    assert_eq!(reader.lines[1].span, 17..27);
+}
+
+#[test]
+fn test_parse_undefined_line_location() {
+    let mut reader = Reader::from_text(
+        "Hello world
+
+    \u{7}Ciao mondo",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics[0].line, 3);
+    assert_eq!(diagnostics[0].span, 4..15);
+}
+
+#[test]
+fn test_parse_text_accented_letter() {
+    SETUP_READER!(reader, "È stato un piacere");
+
+    assert_eq!(reader.lines[0].text, "È stato un piacere");
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_guillemets() {
+    SETUP_READER!(reader, "«Dove sei?»");
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_em_dash() {
+    SETUP_READER!(reader, "— Sì, certo.");
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_digit() {
+    SETUP_READER!(
+        reader,
+        "1984 was a long year
+0 days left"
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_parenthesis() {
+    SETUP_READER!(reader, "(whispers) Hello");
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_other_scripts() {
+    SETUP_READER!(
+        reader,
+        "こんにちは
+¿Qué tal?
+Привет"
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+    assert_eq!(reader.lines[2].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_constant_reference() {
+    SETUP_READER!(
+        reader,
+        "{HELLO} world
+CONST HELLO = \"Hello\""
+    );
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_slash() {
+    SETUP_READER!(reader, "/me waves");
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_text_starts_with_const() {
+    SETUP_READER!(reader, "CONSTANTINOPLE was busy");
+
+    assert_eq!(reader.lines[0].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_control_character() {
+    let mut reader = Reader::from_text("\u{7}Hello world");
+
+    assert_eq!(reader.parse_all_lines().is_err(), true);
+    assert_eq!(reader.lines[0].type_, LineType::Undefined);
+}
+
+#[test]
+fn test_parse_character() {
+    SETUP_READER!(reader, "CHARACTER Anna\nCHARACTERS are here");
+
+    assert_eq!(reader.lines[0].type_, LineType::Character);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_command() {
+    SETUP_READER!(reader, "@bg forest\nMail me @ home");
+
+    assert_eq!(reader.lines[0].type_, LineType::Command);
+    assert_eq!(reader.lines[1].type_, LineType::Text);
+}
+
+#[test]
+fn test_parse_diagnostic_undefined_constant() {
+    let mut reader = Reader::from_text("Hello {WORLD}");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedConstant,
+            1,
+            6..13,
+            "Constant WORLD is not defined"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_question() {
+    let mut reader = Reader::from_text("+ [Hello world -> example");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, ErrorCode::MalformedQuestion);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+    assert_eq!(diagnostics[0].line, 1);
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_constant() {
+    let mut reader = Reader::from_text("CONST HELLO");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, ErrorCode::MalformedConstant);
+    assert_eq!(diagnostics[0].span, 0..11);
+}
+
+#[test]
+fn test_parse_diagnostic_multiple() {
+    let mut reader = Reader::from_text(
+        "Hello {WORLD}
+CONST HELLO
++ [Hello world -> example",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[0].code, ErrorCode::UndefinedConstant);
+    assert_eq!(diagnostics[1].code, ErrorCode::MalformedConstant);
+    assert_eq!(diagnostics[2].code, ErrorCode::MalformedQuestion);
+    assert_eq!(diagnostics[2].line, 3);
+}
+
+#[test]
+fn test_parse_diagnostic_location() {
+    let mut reader = Reader::from_text(
+        "Hello world
+
+// Ciao mondo
+
+    Bonjour {MONDE}",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics[0].line, 5);
+    assert_eq!(diagnostics[0].span, 12..19);
+}
+
+#[test]
+fn test_parse_diagnostic_display() {
+    let diagnostic = Diagnostic::error(
+        ErrorCode::UndefinedConstant,
+        1,
+        6..13,
+        "Constant WORLD is not defined",
+    );
+
+    assert_eq!(
+        diagnostic.to_string(),
+        "error[E0004] at line 1, columns 7-13: Constant WORLD is not defined"
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_undefined_variable() {
+    let mut reader = Reader::from_text("~ score = 1");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedVariable,
+            1,
+            2..7,
+            "Variable score is not defined"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_undefined_variable_increment() {
+    let mut reader = Reader::from_text("~ score++");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedVariable,
+            1,
+            2..7,
+            "Variable score is not defined"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_duplicate_bookmark() {
+    let mut reader = Reader::from_text("=== k\n=== k\nK\n-> k\n= s\n= s\n== s");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![
+            Diagnostic::error(ErrorCode::DuplicateBookmark, 2, 4..5, "Bookmark k is already defined"),
+            Diagnostic::error(ErrorCode::DuplicateBookmark, 6, 2..3, "Bookmark k.s is already defined"),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_undefined_condition() {
+    let mut reader = Reader::from_text("+ {met_anna} [Ask about Anna] -> anna\n== anna");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::UndefinedVariable,
+            1,
+            3..11,
+            "Variable met_anna is not defined"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_inline_conditional() {
+    let mut reader = Reader::from_text("You feel {tired ==: exhausted|fine}.");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::MalformedExpression,
+            1,
+            10..18,
+            "Expression is not complete"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_variable() {
+    let mut reader = Reader::from_text("VAR trust = maybe");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, ErrorCode::MalformedVariable);
+    assert_eq!(diagnostics[0].span, 12..17);
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_expression() {
+    let mut reader = Reader::from_text(
+        "VAR trust = 0
+~ trust = trust +",
+    );
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::MalformedExpression,
+            2,
+            10..17,
+            "Expression is not complete"
+        )]
+    );
+}
+
+#[test]
+fn test_parse_diagnostic_missing_include() {
+    let mut reader = Reader::from_text("INCLUDE stevia_missing_file.ink");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, ErrorCode::MissingInclude);
+    assert_eq!(diagnostics[0].span, 8..31);
+}
+
+#[test]
+fn test_parse_diagnostic_include_cycle() {
+    let path = std::env::temp_dir().join("stevia_include_cycle.ink");
+    std::fs::write(&path, "Hello\nINCLUDE stevia_include_cycle.ink").unwrap();
+
+    let mut reader = Reader::from_file(&path).unwrap();
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::IncludeCycle,
+            2,
+            8..32,
+            "File stevia_include_cycle.ink includes itself"
+        )]
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_parse_diagnostic_malformed_command() {
+    let mut reader = Reader::from_text("Hello\n@show");
+
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![Diagnostic::error(
+            ErrorCode::MalformedCommand,
+            2,
+            0..5,
+            "Cannot parse command"
+        )]
+    );
+}
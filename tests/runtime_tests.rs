@@ -0,0 +1,455 @@
+extern crate stevia;
+
+use stevia::reader::*;
+use stevia::runtime::*;
+use stevia::story::Command;
+use stevia::writer::*;
+
+#[allow(unused_macros)]
+macro_rules! SETUP_PLAYER {
+    ($input:expr, $player:ident) => {
+        let input = $input;
+        let mut reader = Reader::from_text(input);
+        reader.parse_all_lines().unwrap();
+
+        let mut writer = Writer::new();
+        writer.process_lines(&reader).unwrap();
+
+        let mut $player = StoryPlayer::load(&writer.output).unwrap();
+    };
+}
+
+// Reads every paragraph until the story stops
+fn texts(player: &mut StoryPlayer) -> Vec<String> {
+    let mut texts = Vec::new();
+
+    while let Some(paragraph) = player.continue_line().unwrap() {
+        texts.push(paragraph.text);
+    }
+
+    texts
+}
+
+fn labels(player: &StoryPlayer) -> Vec<&str> {
+    player
+        .current_choices()
+        .iter()
+        .map(|choice| choice.label.as_str())
+        .collect()
+}
+
+// --- LOAD ---
+
+#[test]
+fn test_player_load() {
+    let player =
+        StoryPlayer::load("V;trust;0;name;\"Anna\\;Bob\"|N;Anna|R;forest|P;Hello|E;").unwrap();
+
+    assert_eq!(player.records.len(), 5);
+    assert_eq!(player.records[3].offset, 43);
+    assert_eq!(
+        player.variables["trust"],
+        stevia::expression::Token::Number(0)
+    );
+    assert_eq!(
+        player.variables["name"],
+        stevia::expression::Token::Text("Anna;Bob".to_string())
+    );
+    assert_eq!(player.characters, vec!["Anna"]);
+    assert_eq!(player.assets, vec!["forest"]);
+}
+
+#[test]
+fn test_player_load_unknown_record() {
+    let error = StoryPlayer::load("P;Hello|Z;what").unwrap_err();
+
+    assert_eq!(error, "Unknown record Z at 00008");
+}
+
+#[test]
+fn test_player_load_empty() {
+    let mut player = StoryPlayer::load("").unwrap();
+
+    assert_eq!(player.continue_line(), Ok(None));
+    assert!(player.is_ended());
+}
+
+// --- TEXT ---
+
+#[test]
+fn test_player_paragraphs() {
+    SETUP_PLAYER!(
+        "Hello world
+Wait; what? A|B",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["Hello world", "Wait; what? A|B"]);
+    assert!(player.is_ended());
+}
+
+#[test]
+fn test_player_glue() {
+    SETUP_PLAYER!(
+        "Hello <>
+-> next
+=== next
+world
+Bye",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["Hello world", "Bye"]);
+}
+
+// --- QUESTIONS ---
+
+#[test]
+fn test_player_choices() {
+    SETUP_PLAYER!(
+        "Do you like it?
++ [Yes] -> like
++ [No] -> hate
+=== like
+Thank you!
+-> END
+=== hate
+Oh, I see
+-> END",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["Do you like it?"]);
+    assert_eq!(labels(&player), vec!["Yes", "No"]);
+    assert!(!player.is_ended());
+
+    player.choose(1).unwrap();
+
+    assert_eq!(texts(&mut player), vec!["Oh, I see"]);
+    assert!(player.current_choices().is_empty());
+    assert!(player.is_ended());
+}
+
+#[test]
+fn test_player_choose_missing() {
+    SETUP_PLAYER!("+ [Yes] -> END", player);
+
+    texts(&mut player);
+
+    assert_eq!(player.choose(3), Err("There is no choice 4".to_string()));
+}
+
+#[test]
+fn test_player_once_only_choices() {
+    SETUP_PLAYER!(
+        "=== start
+* [Left] -> start
++ [Right] -> END",
+        player
+    );
+
+    assert_eq!(texts(&mut player), Vec::<String>::new());
+    assert_eq!(labels(&player), vec!["Left", "Right"]);
+
+    player.choose(0).unwrap();
+    texts(&mut player);
+
+    assert_eq!(labels(&player), vec!["Right"]);
+}
+
+#[test]
+fn test_player_choice_body_and_gather() {
+    SETUP_PLAYER!(
+        "+ [Hello]
+  Nice to meet you
++ [Bye]
+- Anyway
+-> END",
+        player
+    );
+
+    texts(&mut player);
+    player.choose(0).unwrap();
+
+    assert_eq!(texts(&mut player), vec!["Nice to meet you", "Anyway"]);
+}
+
+#[test]
+fn test_player_escaped_labels() {
+    SETUP_PLAYER!(
+        "VAR name = \"Anna\"
++ [*Sigh*; {name}] -> END",
+        player
+    );
+
+    texts(&mut player);
+
+    assert_eq!(labels(&player), vec!["*Sigh*; Anna"]);
+}
+
+#[test]
+fn test_player_inline_labels() {
+    SETUP_PLAYER!(
+        "VAR tired = false
+-> start
+== start
++ [{tired: Rest; now|Run}] -> rest
++ [{&Hi|Hello} there] -> start
+== rest
+~ tired = true
+-> start",
+        player
+    );
+
+    texts(&mut player);
+    assert_eq!(labels(&player), vec!["Run", "Hi there"]);
+
+    player.choose(1).unwrap();
+    texts(&mut player);
+    assert_eq!(labels(&player), vec!["Run", "Hello there"]);
+
+    player.choose(0).unwrap();
+    texts(&mut player);
+    assert_eq!(labels(&player), vec!["Rest; now", "Hi there"]);
+}
+
+// --- CONDITIONS ---
+
+#[test]
+fn test_player_conditional_choices() {
+    SETUP_PLAYER!(
+        "VAR trust = 0
+=== start
++ {trust > 1} [Kiss] -> END
++ [Wait]
+  ~ trust = trust + 1
+  -> start",
+        player
+    );
+
+    texts(&mut player);
+    assert_eq!(labels(&player), vec!["Wait"]);
+
+    player.choose(0).unwrap();
+    texts(&mut player);
+    player.choose(0).unwrap();
+    texts(&mut player);
+
+    assert_eq!(labels(&player), vec!["Kiss", "Wait"]);
+    assert_eq!(
+        player.variables["trust"],
+        stevia::expression::Token::Number(2)
+    );
+}
+
+#[test]
+fn test_player_variables_and_conditionals() {
+    SETUP_PLAYER!(
+        "VAR gold = 5
+~ gold = gold * 2
+You have {gold} coins, {gold > 5:rich|poor}.",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["You have 10 coins, rich."]);
+}
+
+#[test]
+fn test_player_visits() {
+    SETUP_PLAYER!(
+        "-> room
+=== room
+{room > 1:Again|First} here
++ [Stay] -> room",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["First here"]);
+
+    player.choose(0).unwrap();
+
+    assert_eq!(texts(&mut player), vec!["Again here"]);
+}
+
+#[test]
+fn test_player_division_by_zero() {
+    SETUP_PLAYER!(
+        "VAR gold = 0
+~ gold = 1 / gold",
+        player
+    );
+
+    assert_eq!(player.continue_line(), Err("Division by zero".to_string()));
+}
+
+#[test]
+fn test_player_endless_loop() {
+    let mut player = StoryPlayer::load("J;00000").unwrap();
+
+    assert_eq!(
+        player.continue_line(),
+        Err("Jump to 00000 loops forever".to_string())
+    );
+
+    SETUP_PLAYER!(
+        "=== a
+Hello <>
+-> a",
+        glued
+    );
+
+    assert_eq!(
+        glued.continue_line(),
+        Err("Jump to 00000 loops forever".to_string())
+    );
+}
+
+#[test]
+fn test_player_loop_with_text() {
+    SETUP_PLAYER!(
+        "-> a
+=== a
+-> b
+=== b
+Text
+-> a",
+        player
+    );
+
+    for _ in 0..3 {
+        assert_eq!(player.continue_line().unwrap().unwrap().text, "Text");
+    }
+}
+
+// --- SEQUENCES ---
+
+#[test]
+fn test_player_sequences() {
+    SETUP_PLAYER!(
+        "=== start
+{One|Two} {&Red|Blue} {!Once}.
++ [Again] -> start",
+        player
+    );
+
+    let mut seen = Vec::new();
+
+    for _ in 0..3 {
+        seen.extend(texts(&mut player));
+        player.choose(0).unwrap();
+    }
+
+    assert_eq!(seen, vec!["One Red Once.", "Two Blue .", "Two Red ."]);
+}
+
+// --- END ---
+
+#[test]
+fn test_player_done() {
+    SETUP_PLAYER!(
+        "-> a
+== a
+In a
+-> DONE
+== b
+Secret b
+-> END",
+        player
+    );
+
+    assert_eq!(texts(&mut player), vec!["In a"]);
+    assert!(player.is_done());
+    assert!(!player.is_ended());
+
+    assert_eq!(texts(&mut player), Vec::<String>::new());
+    assert!(player.is_done());
+}
+
+// --- TAGS ---
+
+#[test]
+fn test_player_tags() {
+    SETUP_PLAYER!("Hello #happy #loud\nBye", player);
+
+    let paragraph = player.continue_line().unwrap().unwrap();
+
+    assert_eq!(paragraph.text, "Hello");
+    assert_eq!(paragraph.tags, vec!["happy", "loud"]);
+    assert!(player.continue_line().unwrap().unwrap().tags.is_empty());
+}
+
+// --- DIALOGUE ---
+
+#[test]
+fn test_player_dialogue() {
+    SETUP_PLAYER!("CHARACTER Anna\nAnna: Hello", player);
+
+    let paragraph = player.continue_line().unwrap().unwrap();
+
+    assert_eq!(paragraph.speaker, Some("Anna".to_string()));
+    assert_eq!(paragraph.text, "Hello");
+}
+
+// --- COMMANDS ---
+
+#[test]
+fn test_player_commands() {
+    SETUP_PLAYER!(
+        "@bg forest
+@show anna happy left
+Hello
+@music theme
+Bye",
+        player
+    );
+
+    assert_eq!(player.continue_line().unwrap().unwrap().text, "Hello");
+    assert_eq!(
+        player.take_commands(),
+        vec![
+            Command::Background("forest".to_string()),
+            Command::Show {
+                character: "anna".to_string(),
+                asset: "anna_happy".to_string(),
+                position: Some("left".to_string()),
+            },
+        ]
+    );
+
+    assert_eq!(player.continue_line().unwrap().unwrap().text, "Bye");
+    assert_eq!(
+        player.take_commands(),
+        vec![Command::Music("theme".to_string())]
+    );
+    assert!(player.take_commands().is_empty());
+}
+
+// --- JUMPS ---
+
+#[test]
+fn test_player_go_to() {
+    let input = "Hello
+-> END
+=== later
+Bye
+-> END";
+
+    let mut reader = Reader::from_text(input);
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = Writer::new();
+    writer.process_lines(&reader).unwrap();
+
+    let mut player = StoryPlayer::load(&writer.output).unwrap();
+
+    assert_eq!(texts(&mut player), vec!["Hello"]);
+    assert!(player.is_ended());
+
+    player.go_to(writer.symbols["later"]).unwrap();
+
+    assert_eq!(texts(&mut player), vec!["Bye"]);
+    assert_eq!(
+        player.go_to(1),
+        Err("There is no record at 00001".to_string())
+    );
+}
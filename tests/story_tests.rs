@@ -0,0 +1,662 @@
+extern crate stevia;
+
+use stevia::expression::*;
+use stevia::reader::*;
+use stevia::story::*;
+
+#[allow(unused_macros)]
+macro_rules! SETUP_STORY {
+    ($story:ident, $input:expr) => {
+        let input = $input;
+        let mut reader = Reader::from_text(input);
+        reader.parse_all_lines().unwrap();
+        let $story = reader.story;
+    };
+}
+
+#[test]
+fn test_story_paragraphs() {
+    SETUP_STORY!(
+        story,
+        "Hello world
+Ciao mondo"
+    );
+
+    assert_eq!(
+        story.nodes,
+        vec![
+            Node::Paragraph(vec![Segment::Text("Hello world".to_string())]),
+            Node::Paragraph(vec![Segment::Text("Ciao mondo".to_string())]),
+        ]
+    );
+    assert!(story.knots.is_empty());
+}
+
+#[test]
+fn test_story_constant_segments() {
+    SETUP_STORY!(
+        story,
+        "CONST WORLD = \"mondo\"
+Ciao {WORLD}!"
+    );
+
+    assert_eq!(
+        story.nodes,
+        vec![Node::Paragraph(vec![
+            Segment::Text("Ciao ".to_string()),
+            Segment::Constant("WORLD".to_string()),
+            Segment::Text("!".to_string()),
+        ])]
+    );
+    assert_eq!(
+        story.text(&[Segment::Constant("WORLD".to_string())]),
+        "mondo"
+    );
+}
+
+#[test]
+fn test_story_constant_declared_later() {
+    SETUP_STORY!(
+        story,
+        "Ciao {WORLD}
+CONST WORLD = \"mondo\""
+    );
+
+    assert_eq!(story.constants.get("WORLD").unwrap(), "mondo");
+}
+
+#[test]
+fn test_story_choices_grouped() {
+    SETUP_STORY!(
+        story,
+        "Hello
++ [Yes] -> yes
+// Maybe later
++ [No] -> no
+== yes
+== no"
+    );
+
+    assert_eq!(story.nodes.len(), 2);
+
+    match &story.nodes[1] {
+        Node::Choices(choices) => {
+            assert_eq!(choices.len(), 2);
+            assert_eq!(choices[0].label, vec![Segment::Text("Yes".to_string())]);
+            assert_eq!(choices[0].target.as_ref().unwrap().name, "yes");
+            assert_eq!(choices[1].target.as_ref().unwrap().name, "no");
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_choices_once_only() {
+    SETUP_STORY!(
+        story,
+        "* [Yes] -> yes
++ [No] -> no
+== yes
+== no"
+    );
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            assert!(choices[0].once);
+            assert!(!choices[1].once);
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_choice_output() {
+    SETUP_STORY!(
+        story,
+        "+ Hello [back!] right back to you! -> hello
++ [Ask] \"Where is it?\" -> hello
++ Goodbye [then] -> hello
++ [Nothing] -> hello
+== hello"
+    );
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            assert_eq!(story.text(&choices[0].label), "Hello back!");
+            assert_eq!(story.text(&choices[0].output), "Hello right back to you!");
+
+            assert_eq!(story.text(&choices[1].label), "Ask");
+            assert_eq!(story.text(&choices[1].output), "\"Where is it?\"");
+
+            assert_eq!(story.text(&choices[2].label), "Goodbye then");
+            assert_eq!(story.text(&choices[2].output), "Goodbye");
+
+            assert_eq!(story.text(&choices[3].label), "Nothing");
+            assert!(choices[3].output.is_empty());
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_choice_output_constant() {
+    let mut reader = Reader::from_text("+ [Ask] about {THING} -> hello\n== hello");
+    let diagnostics = reader.parse_all_lines().unwrap_err();
+
+    assert_eq!(diagnostics[0].span, 14..21);
+}
+
+#[test]
+fn test_story_choice_body_and_gather() {
+    SETUP_STORY!(
+        story,
+        "+ [Where?]
+  Over there.
+  // Pointing
+  Far away.
++ [When?] -> later
+- They left.
+== later"
+    );
+
+    assert_eq!(story.nodes.len(), 2);
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            assert_eq!(choices.len(), 2);
+            assert_eq!(choices[0].target, None);
+            assert_eq!(
+                choices[0].body,
+                vec![
+                    Node::Paragraph(vec![Segment::Text("Over there.".to_string())]),
+                    Node::Paragraph(vec![Segment::Text("Far away.".to_string())]),
+                ]
+            );
+            assert!(choices[1].body.is_empty());
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+
+    assert_eq!(
+        story.nodes[1],
+        Node::Paragraph(vec![Segment::Text("They left.".to_string())])
+    );
+}
+
+#[test]
+fn test_story_gather_with_divert() {
+    SETUP_STORY!(
+        story,
+        "+ [Yes]
++ [No]
+- -> END"
+    );
+
+    assert_eq!(story.nodes.len(), 2);
+    assert_eq!(story.nodes[1], Node::End);
+}
+
+#[test]
+fn test_story_nested_choices() {
+    SETUP_STORY!(
+        story,
+        "* [A]
+  * * [A1] Deep
+  * * [A2]
+    * * * [A2a] -> END
+  - - Back
+* [B]
+- End"
+    );
+
+    assert_eq!(story.nodes.len(), 2);
+
+    let choices = match &story.nodes[0] {
+        Node::Choices(choices) => choices,
+        node => panic!("Expected choices, found {:?}", node),
+    };
+
+    assert_eq!(choices.len(), 2);
+    assert_eq!(choices[0].body.len(), 2);
+
+    match &choices[0].body[0] {
+        Node::Choices(nested) => {
+            assert_eq!(nested.len(), 2);
+            assert_eq!(story.text(&nested[0].output), "Deep");
+            assert!(nested[0].once);
+            assert_eq!(nested[1].body.len(), 1);
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+
+    assert_eq!(
+        choices[0].body[1],
+        Node::Paragraph(vec![Segment::Text("Back".to_string())])
+    );
+    assert!(choices[1].body.is_empty());
+}
+
+#[test]
+fn test_story_choices_separated_by_text() {
+    SETUP_STORY!(
+        story,
+        "+ [Yes] -> yes
+Hello
++ [No] -> no
+== yes
+== no"
+    );
+
+    assert_eq!(story.nodes.len(), 3);
+}
+
+#[test]
+fn test_story_knots_and_stitches() {
+    SETUP_STORY!(
+        story,
+        "Intro
+== train
+Choo choo
+= carriage
+-> END
+== station
+-> train.carriage"
+    );
+
+    assert_eq!(
+        story.bookmarks(),
+        vec!["train", "train.carriage", "station"]
+    );
+
+    assert_eq!(story.knots[0].nodes.len(), 1);
+    assert_eq!(story.knots[0].stitches[0].nodes, vec![Node::End]);
+
+    match &story.knots[1].nodes[0] {
+        Node::Jump(target) => assert_eq!(target.name, "train.carriage"),
+        node => panic!("Expected a jump, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_local_stitch_target() {
+    SETUP_STORY!(
+        story,
+        "== train
+-> carriage
+= carriage
+Hello"
+    );
+
+    assert_eq!(
+        story.knots[0].nodes,
+        vec![Node::Jump(Target {
+            name: "train.carriage".to_string(),
+            file: 0,
+            line: 2,
+            span: 3..11,
+        })]
+    );
+}
+
+#[test]
+fn test_story_target_location() {
+    SETUP_STORY!(
+        story,
+        "Hello
+  + [Yes] -> yes
+== yes"
+    );
+
+    match &story.nodes[1] {
+        Node::Choices(choices) => {
+            assert_eq!(choices[0].target.as_ref().unwrap().line, 2);
+            assert_eq!(choices[0].target.as_ref().unwrap().span, 13..16);
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_variables() {
+    SETUP_STORY!(
+        story,
+        "Trust is {trust}
+~ trust = 1
+VAR trust = 0"
+    );
+
+    assert_eq!(
+        story.variables,
+        vec![Variable {
+            name: "trust".to_string(),
+            value: Token::Number(0),
+        }]
+    );
+
+    assert_eq!(
+        story.nodes[0],
+        Node::Paragraph(vec![
+            Segment::Text("Trust is ".to_string()),
+            Segment::Variable("trust".to_string()),
+        ])
+    );
+
+    assert_eq!(
+        story.nodes[1],
+        Node::Assignment("trust".to_string(), Expression::parse("1").unwrap())
+    );
+
+    assert_eq!(story.text(&[Segment::Variable("trust".to_string())]), "0");
+}
+
+#[test]
+fn test_story_conditional_choice() {
+    SETUP_STORY!(
+        story,
+        "VAR met_anna = false
++ {met_anna} {anna > 1} [Ask about Anna] -> anna
++ [Leave] -> anna
+== anna"
+    );
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            let condition = choices[0].condition.as_ref().unwrap();
+
+            assert_eq!(condition.text, "met_anna and anna > 1");
+            assert_eq!(
+                condition.expression.tokens,
+                vec![
+                    Token::Variable("met_anna".to_string()),
+                    Token::Visits("anna".to_string()),
+                    Token::Number(1),
+                    Token::Operator(">"),
+                    Token::Operator("and"),
+                ]
+            );
+            assert_eq!(story.text(&choices[0].label), "Ask about Anna");
+            assert_eq!(choices[1].condition, None);
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_condition_with_brackets() {
+    SETUP_STORY!(
+        story,
+        r#"VAR name = "a"
++ {name == "["} [x] Then -> k
+== k"#
+    );
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            assert_eq!(
+                choices[0].condition.as_ref().unwrap().text,
+                r#"name == "[""#
+            );
+            assert_eq!(story.text(&choices[0].label), "x");
+            assert_eq!(story.text(&choices[0].output), "Then");
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_condition_local_stitch() {
+    SETUP_STORY!(
+        story,
+        "== train
++ {carriage} [Again] -> carriage
+= carriage
+Hello"
+    );
+
+    match &story.knots[0].nodes[0] {
+        Node::Choices(choices) => assert_eq!(
+            choices[0].condition.as_ref().unwrap().expression.tokens,
+            vec![Token::Visits("train.carriage".to_string())]
+        ),
+        node => panic!("Expected choices, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_inline_conditional() {
+    SETUP_STORY!(
+        story,
+        "VAR tired = true
+You feel {tired: exhausted|fine}.
+{not tired: Rested}"
+    );
+
+    match &story.nodes[0] {
+        Node::Paragraph(segments) => {
+            match &segments[1] {
+                Segment::Conditional(condition, then, otherwise) => {
+                    assert_eq!(condition.text, "tired");
+                    assert_eq!(then, "exhausted");
+                    assert_eq!(otherwise, "fine");
+                }
+                segment => panic!("Expected a conditional, found {:?}", segment),
+            }
+
+            assert_eq!(story.text(segments), "You feel exhausted.");
+        }
+        node => panic!("Expected a paragraph, found {:?}", node),
+    }
+
+    match &story.nodes[1] {
+        Node::Paragraph(segments) => assert_eq!(story.text(segments), ""),
+        node => panic!("Expected a paragraph, found {:?}", node),
+    }
+}
+
+#[test]
+fn test_story_sequences() {
+    SETUP_STORY!(
+        story,
+        "{Hello|Hello again|Hi}
+{&Mon|Tue|Wed}
+{!Once}
+{~Heads|Tails}"
+    );
+
+    let kinds: Vec<SequenceKind> = story
+        .nodes
+        .iter()
+        .map(|node| match node {
+            Node::Paragraph(segments) => match &segments[0] {
+                Segment::Sequence(kind, _) => *kind,
+                segment => panic!("Expected a sequence, found {:?}", segment),
+            },
+            node => panic!("Expected a paragraph, found {:?}", node),
+        })
+        .collect();
+
+    assert_eq!(
+        kinds,
+        vec![
+            SequenceKind::Stopping,
+            SequenceKind::Cycle,
+            SequenceKind::Once,
+            SequenceKind::Shuffle,
+        ]
+    );
+
+    assert_eq!(
+        story.nodes[1],
+        Node::Paragraph(vec![Segment::Sequence(
+            SequenceKind::Cycle,
+            vec!["Mon".to_string(), "Tue".to_string(), "Wed".to_string()]
+        )])
+    );
+}
+
+#[test]
+fn test_story_tags() {
+    SETUP_STORY!(
+        story,
+        "Hello # loud
++ [Yes] -> forest # happy
+== forest
+# bg: forest
+Trees"
+    );
+
+    assert_eq!(story.nodes[0], Node::Tags(vec!["loud".to_string()]));
+
+    match &story.nodes[2] {
+        Node::Choices(choices) => assert_eq!(choices[0].tags, vec!["happy"]),
+        node => panic!("Expected choices, found {:?}", node),
+    }
+
+    assert_eq!(
+        story.knots[0].nodes[0],
+        Node::Tags(vec!["bg: forest".to_string()])
+    );
+}
+
+#[test]
+fn test_story_glue() {
+    SETUP_STORY!(
+        story,
+        "CONST NAME = \"Anna\"
+Hello <>
+{NAME}
+<>, how are you?
+We hurried home <>
+-> savile_row
+== savile_row
+to Savile Row."
+    );
+
+    assert_eq!(story.nodes.len(), 4);
+    assert_eq!(
+        story.nodes[0],
+        Node::Paragraph(vec![
+            Segment::Text("Hello ".to_string()),
+            Segment::Constant("NAME".to_string()),
+            Segment::Text(", how are you?".to_string()),
+        ])
+    );
+    assert_eq!(story.nodes[2], Node::Glue);
+}
+
+#[test]
+fn test_story_end_in_choices() {
+    SETUP_STORY!(
+        story,
+        "+ [Quit] -> END
++ [Pause] -> DONE
+Hello"
+    );
+
+    match &story.nodes[0] {
+        Node::Choices(choices) => {
+            assert_eq!(choices[0].target, None);
+            assert_eq!(choices[0].body, vec![Node::End]);
+            assert_eq!(choices[1].body, vec![Node::Done]);
+            assert!(choices[1].diverts());
+        }
+        node => panic!("Expected choices, found {:?}", node),
+    }
+
+    assert!(story.nodes[0].diverts());
+    assert_eq!(story.nodes.len(), 2);
+}
+
+#[test]
+fn test_story_dialogue() {
+    SETUP_STORY!(
+        story,
+        "CHARACTER Anna, Mr Smith
+CHARACTER Anna
+Anna: Hello <>
+there!
+Mr Smith:Good morning
+Note: this is not dialogue"
+    );
+
+    assert_eq!(story.characters, vec!["Anna", "Mr Smith"]);
+    assert_eq!(
+        story.nodes,
+        vec![
+            Node::Dialogue(
+                0,
+                vec![
+                    Segment::Text("Hello ".to_string()),
+                    Segment::Text("there!".to_string()),
+                ]
+            ),
+            Node::Dialogue(1, vec![Segment::Text("Good morning".to_string())]),
+            Node::Paragraph(vec![Segment::Text(
+                "Note: this is not dialogue".to_string()
+            )]),
+        ]
+    );
+}
+
+#[test]
+fn test_story_gather_dialogue_and_glue() {
+    SETUP_STORY!(
+        story,
+        "CHARACTER Anna
++ [Yes]
++ [No]
+- Anna: gathered <>
+- <> again"
+    );
+
+    assert_eq!(
+        story.nodes[1..],
+        [Node::Dialogue(
+            0,
+            vec![
+                Segment::Text("gathered ".to_string()),
+                Segment::Text(" again".to_string()),
+            ]
+        )]
+    );
+}
+
+#[test]
+fn test_story_commands() {
+    SETUP_STORY!(
+        story,
+        "@bg forest
+@show anna
+@show anna happy
+@show anna right
+== park
+@show bob sad center
+@bg forest"
+    );
+
+    assert_eq!(
+        story.nodes,
+        vec![
+            Node::Command(Command::Background("forest".to_string())),
+            Node::Command(Command::Show {
+                character: "anna".to_string(),
+                asset: "anna".to_string(),
+                position: None,
+            }),
+            Node::Command(Command::Show {
+                character: "anna".to_string(),
+                asset: "anna_happy".to_string(),
+                position: None,
+            }),
+            Node::Command(Command::Show {
+                character: "anna".to_string(),
+                asset: "anna".to_string(),
+                position: Some("right".to_string()),
+            }),
+        ]
+    );
+    assert_eq!(
+        story.assets(),
+        vec!["forest", "anna", "anna_happy", "bob_sad"]
+    );
+}
@@ -1,5 +1,6 @@
 extern crate stevia;
 
+use stevia::diagnostic::*;
 use stevia::reader::*;
 use stevia::writer::*;
 
@@ -8,10 +9,27 @@ macro_rules! SETUP_WRITER {
     ($input:expr, $reader:ident, $writer:ident) => {
         let input = $input;
         let mut $reader = Reader::from_text(input);
-        $reader.parse_all_lines();
+        $reader.parse_all_lines().unwrap();
 
         let mut $writer = Writer::new();
-        $writer.process_lines(&$reader);
+        $writer.process_lines(&$reader).unwrap();
+    };
+}
+
+// Jumps to undefined knots are reported, but the output is written anyway
+#[allow(unused_macros)]
+macro_rules! SETUP_FAKE_WRITER {
+    ($input:expr, $reader:ident, $writer:ident) => {
+        let input = $input;
+        let mut $reader = Reader::from_text(input);
+        $reader.parse_all_lines().unwrap();
+
+        let mut $writer = Writer::new();
+        let diagnostics = $writer.process_lines(&$reader).unwrap_err();
+
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.code == ErrorCode::UndefinedBookmark));
     };
 }
 
@@ -62,11 +80,55 @@ Bonjour monde"#,
     assert_eq!(writer.index, 42);
 }
 
+#[test]
+fn test_writer_print_unicode() {
+    SETUP_WRITER!(
+        "È stato un piacere
+«Dove sei?»",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;È stato un piacere|P;«Dove sei?»");
+
+    assert_eq!(writer.index, writer.output.len());
+}
+
+#[test]
+fn test_writer_glue() {
+    SETUP_WRITER!(
+        "Hello <>
+world
+<> and goodbye",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Hello world and goodbye");
+}
+
+#[test]
+fn test_writer_glue_across_jump() {
+    SETUP_WRITER!(
+        "We hurried home <>
+-> savile_row
+== savile_row
+to Savile Row.",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "P;We hurried home |G;|J;00030|P;to Savile Row."
+    );
+}
+
 // --- QUESTIONS ---
 
 #[test]
 fn test_writer_question_fake_jump_one() {
-    SETUP_WRITER!("+ [Hello world] -> example", reader, writer);
+    SETUP_FAKE_WRITER!("+ [Hello world] -> example", reader, writer);
 
     SETUP_SYMBOLS!(String::from("example"), 0, writer);
 
@@ -77,7 +139,7 @@ fn test_writer_question_fake_jump_one() {
 
 #[test]
 fn test_writer_question_fake_jump_two() {
-    SETUP_WRITER!(
+    SETUP_FAKE_WRITER!(
         "+ [Hello world] -> example
 + [Ciao mondo] -> sample",
         reader,
@@ -94,8 +156,39 @@ fn test_writer_question_fake_jump_two() {
 }
 
 #[test]
-fn test_writer_question_fake_jump_and_print() {
+fn test_writer_question_once_only() {
+    SETUP_FAKE_WRITER!(
+        "* [Hello world] -> example
++ [Ciao mondo] -> sample",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "Q;*Hello world;00000;Ciao mondo;00000");
+
+    assert_eq!(writer.branch_table["example"], vec![15]);
+    assert_eq!(writer.branch_table["sample"], vec![32]);
+}
+
+#[test]
+fn test_writer_question_output() {
     SETUP_WRITER!(
+        "+ [Ask] \"Where is it?\" -> example
+=== example
+Here",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;Ask;00012|P;\"Where is it?\"|J;00037|P;Here"
+    );
+}
+
+#[test]
+fn test_writer_question_fake_jump_and_print() {
+    SETUP_FAKE_WRITER!(
         "+ [Hello world] -> example
 + [Ciao mondo] -> sample
 Bonjour monde",
@@ -117,7 +210,7 @@ Bonjour monde",
 
 #[test]
 fn test_writer_question_fake_jump_multiple() {
-    SETUP_WRITER!(
+    SETUP_FAKE_WRITER!(
         "+ [Hello world] -> example
 + [Ciao mondo] -> sample
 Bonjour monde
@@ -170,6 +263,75 @@ Ciao mondo
     assert_eq!(writer.symbols["sample"], 51);
 }
 
+// --- GATHERS ---
+
+#[test]
+fn test_writer_gather_one() {
+    SETUP_WRITER!(
+        "What do you ask?
++ [Where?]
+  Over there.
++ [When?] -> later
+- They left.
+== later
+Now",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "P;What do you ask?|Q;Where?;00046;When?;00081|P;Over there.|J;00068|P;They left.|P;Now"
+    );
+}
+
+#[test]
+fn test_writer_gather_empty_choices() {
+    SETUP_WRITER!(
+        "+ [Yes]
++ [No]
+- Ok",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "Q;Yes;00021;No;00021|P;Ok");
+}
+
+#[test]
+fn test_writer_gather_body_with_divert() {
+    SETUP_WRITER!(
+        "+ [Yes]
+  -> END
++ [No]
+- Ok",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "Q;Yes;00021;No;00024|E;|P;Ok");
+}
+
+#[test]
+fn test_writer_gather_nested_choices() {
+    SETUP_WRITER!(
+        "+ [A]
+  + + [A1]
+    Deep
+  + + [A2]
+  - - Back
++ [B]
+- End",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;A;00018;B;00068|Q;A1;00038;A2;00053|P;Deep|J;00053|P;Back|J;00068|P;End"
+    );
+}
+
 // --- END ---
 
 #[test]
@@ -195,6 +357,135 @@ fn test_writer_end_two() {
     assert_eq!(writer.index, 16);
 }
 
+#[test]
+fn test_writer_done() {
+    SETUP_WRITER!(
+        "Hello world
+-> DONE",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Hello world|D;");
+}
+
+#[test]
+fn test_writer_end_in_choices() {
+    SETUP_WRITER!(
+        "+ [Quit] -> END
++ [Pause] -> DONE
++ [Stay] Staying.
+- Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;Quit;00036;Pause;00039;Stay;00042|E;|D;|P;Staying.|J;00061|P;Hello"
+    );
+}
+
+// --- JUMPS ---
+
+#[test]
+fn test_writer_jump_fake_one() {
+    SETUP_FAKE_WRITER!("-> example", reader, writer);
+
+    assert_eq!(writer.output, "J;00000");
+
+    assert_eq!(writer.index, 7);
+
+    assert_eq!(writer.branch_table["example"], vec![2]);
+}
+
+#[test]
+fn test_writer_undefined_bookmark() {
+    let mut reader = Reader::from_text(
+        "Hello
+-> nowhere
++ [Go] -> nowhere_else
+== a
+A",
+    );
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = Writer::new();
+    let diagnostics = writer.process_lines(&reader).unwrap_err();
+
+    assert_eq!(
+        diagnostics,
+        vec![
+            Diagnostic::error(
+                ErrorCode::UndefinedBookmark,
+                2,
+                3..10,
+                "Bookmark nowhere is not defined"
+            ),
+            Diagnostic::error(
+                ErrorCode::UndefinedBookmark,
+                3,
+                10..22,
+                "Bookmark nowhere_else is not defined"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_writer_jump_forward() {
+    SETUP_WRITER!(
+        "Hello world
+-> example
+Ciao mondo
+=== example
+Bonjour monde",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "P;Hello world|J;00035|P;Ciao mondo|P;Bonjour monde"
+    );
+
+    assert_eq!(writer.branch_table["example"], vec![16]);
+    assert_eq!(writer.symbols["example"], 35);
+
+    assert_eq!(reader.lines[1].type_, LineType::Jump);
+}
+
+#[test]
+fn test_writer_jump_backward() {
+    SETUP_WRITER!(
+        "=== example
+Hello world
+-> example",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Hello world|J;00000");
+
+    assert_eq!(writer.branch_table["example"], vec![16]);
+    assert_eq!(writer.symbols["example"], 0);
+}
+
+#[test]
+fn test_writer_jump_and_question() {
+    SETUP_WRITER!(
+        "-> question
+=== question
++ [Hello world] -> question",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "J;00008|Q;Hello world;00008");
+
+    assert_eq!(writer.branch_table["question"], vec![2, 22]);
+}
+
 // --- BOOKMARKS ---
 
 #[test]
@@ -282,6 +573,108 @@ Bonjour monde",
     assert_eq!(writer.symbols["world"], 27);
 }
 
+#[test]
+fn test_writer_bookmark_in_included_file() {
+    let directory = std::env::temp_dir().join("stevia_writer_include");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("main.ink"), "-> one\nINCLUDE one.ink").unwrap();
+    std::fs::write(directory.join("one.ink"), "== one\nHello").unwrap();
+
+    let mut reader = Reader::from_file(&directory.join("main.ink")).unwrap();
+    reader.parse_all_lines().unwrap();
+
+    let mut writer = Writer::new();
+    writer.process_lines(&reader).unwrap();
+
+    assert_eq!(writer.output, "J;00008|P;Hello");
+    assert_eq!(writer.symbols["one"], 8);
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+// --- STITCHES ---
+
+#[test]
+fn test_writer_stitch_one() {
+    SETUP_WRITER!(
+        "=== hello
+Hello world
+= world
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Hello world|P;Ciao mondo");
+
+    assert_eq!(writer.symbols["hello"], 0);
+    assert_eq!(writer.symbols["hello.world"], 14);
+}
+
+#[test]
+fn test_writer_stitch_same_name() {
+    SETUP_WRITER!(
+        "=== hello
+= start
+Hello world
+=== ciao
+= start
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.symbols["hello.start"], 0);
+    assert_eq!(writer.symbols["ciao.start"], 14);
+
+    assert_eq!(writer.symbols.contains_key("start"), false);
+}
+
+#[test]
+fn test_writer_stitch_local_jump() {
+    SETUP_WRITER!(
+        "=== hello
+-> end
+= end
+Hello world
+=== ciao
+-> end
+= end
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "J;00008|P;Hello world|J;00030|P;Ciao mondo");
+
+    assert_eq!(writer.branch_table["hello.end"], vec![2]);
+    assert_eq!(writer.branch_table["ciao.end"], vec![24]);
+}
+
+#[test]
+fn test_writer_stitch_qualified_jump() {
+    SETUP_WRITER!(
+        "+ [Hello world] -> ciao.end
+-> hello.end
+=== hello
+= end
+Hello world
+=== ciao
+= end
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;Hello world;00042|J;00028|P;Hello world|P;Ciao mondo"
+    );
+
+    assert_eq!(writer.symbols["hello.end"], 28);
+    assert_eq!(writer.symbols["ciao.end"], 42);
+}
+
 // --- CONSTANTS ---
 
 #[test]
@@ -387,6 +780,316 @@ Hello {HELLO} Ciao {CIAO}",
     assert_eq!(writer.constants["CIAO"], "Mondo");
 }
 
+// --- VARIABLES ---
+
+#[test]
+fn test_writer_variable_header() {
+    SETUP_WRITER!(
+        r#"VAR trust = 0
+VAR name = "Bob"
+Hello"#,
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, r#"V;trust;0;name;"Bob"|P;Hello"#);
+}
+
+#[test]
+fn test_writer_variable_assignment() {
+    SETUP_WRITER!(
+        "VAR trust = 0
+~ trust = trust + 1
+~ trust++
+~ trust -= 2",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;trust;0|L;trust;trust 1 +|L;trust;trust 1 +|L;trust;trust 2 -"
+    );
+}
+
+#[test]
+fn test_writer_variable_print() {
+    SETUP_WRITER!(
+        "VAR trust = 0
+Trust is {trust}.
+{trust}",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;trust;0|P;Trust is |I;trust|G;.|P;|I;trust"
+    );
+}
+
+#[test]
+fn test_writer_variable_jump_place() {
+    SETUP_WRITER!(
+        "VAR trust = 0
+-> hello
+== hello
+Trust is {trust}.",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "V;trust;0|J;00018|P;Trust is |I;trust|G;.");
+}
+
+#[test]
+fn test_writer_variable_question_label() {
+    SETUP_FAKE_WRITER!(
+        "VAR trust = 0
++ [Trust {trust}] -> hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "V;trust;0|Q;Trust {trust};00000");
+}
+
+#[test]
+fn test_writer_inline_question_label() {
+    SETUP_WRITER!(
+        "VAR tired = false
++ [{tired: Rest; now|Run} {&a|b}] -> t
+== t",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;tired;false|Q;{C\\;tired\\;Rest\\\\\\; now\\;Run} {S\\;cycle\\;0\\;a\\;b};00072"
+    );
+}
+
+// --- CONDITIONS ---
+
+#[test]
+fn test_writer_conditional_choice() {
+    SETUP_WRITER!(
+        "VAR met_anna = false
++ {met_anna} [Ask about Anna] -> anna
++ [Leave] -> anna
+== anna
+Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;met_anna;false|Q;[met_anna]Ask about Anna;00062;Leave;00062|P;Hello"
+    );
+}
+
+#[test]
+fn test_writer_conditional_choice_visits() {
+    SETUP_WRITER!(
+        "VAR met_anna = false
+* {met_anna} {not anna} [Ask about Anna] -> anna
+== anna
+Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;met_anna;false|Q;*[met_anna @00066 not and]Ask about Anna;00066|P;Hello"
+    );
+}
+
+#[test]
+fn test_writer_inline_conditional() {
+    SETUP_WRITER!(
+        "VAR tired = false
+You feel {tired: exhausted|fine}.
+{tired: Yawn}",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;tired;false|P;You feel |C;tired;exhausted;fine|G;.|P;|C;tired;Yawn;"
+    );
+}
+
+#[test]
+fn test_writer_inline_conditional_visits() {
+    SETUP_WRITER!(
+        "-> station
+== station
+{station > 1: Back again}",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "J;00008|P;|C;@00008 1 >;Back again;");
+}
+
+// --- SEQUENCES ---
+
+#[test]
+fn test_writer_sequences() {
+    SETUP_WRITER!(
+        "Today is {&Monday|Tuesday}.
+{Hello|Hello again} and {~heads|tails}",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "P;Today is |S;cycle;0;Monday;Tuesday|G;.|P;|S;stop;1;Hello;Hello again|G; and |S;shuffle;2;heads;tails"
+    );
+
+    assert_eq!(writer.sequences, 3);
+}
+
+// --- TAGS ---
+
+#[test]
+fn test_writer_tags() {
+    SETUP_WRITER!(
+        "# bg: forest
+Hello # loud # angry",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "T;bg: forest|T;loud;angry|P;Hello");
+}
+
+#[test]
+fn test_writer_tags_choice() {
+    SETUP_WRITER!(
+        "+ [Yes] -> yes # happy
++ [No] -> yes
+== yes
+Hello",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;Yes;00021;No;00037|T;happy|J;00037|P;Hello"
+    );
+}
+
+// --- DIALOGUE ---
+
+#[test]
+fn test_writer_dialogue() {
+    SETUP_WRITER!(
+        "VAR mood = \"fine\"
+CHARACTER Anna, Bob
+Bob: Hi!
+Anna: I'm {mood}.
+Bye",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "V;mood;\"fine\"|N;Anna;Bob|A;1;Hi!|A;0;I'm |I;mood|G;.|P;Bye"
+    );
+}
+
+#[test]
+fn test_writer_dialogue_after_gather() {
+    SETUP_WRITER!(
+        "CHARACTER Anna
++ [Yes]
++ [No]
+- Anna: gathered",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "N;Anna|Q;Yes;00028;No;00028|A;0;gathered");
+}
+
+// --- COMMANDS ---
+
+#[test]
+fn test_writer_commands() {
+    SETUP_WRITER!(
+        "@bg forest
+@show anna happy left
+@hide anna
+@music theme
+@sfx door
+@bg forest",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "R;forest;anna_happy;theme;door|B;0|F;anna;1;left|H;anna|M;2|X;3|B;0"
+    );
+}
+
+// --- ESCAPING ---
+
+#[test]
+fn test_writer_escape_text() {
+    SETUP_WRITER!(
+        "Wait; what?
+A|B and C\\D",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Wait\\; what?|P;A\\|B and C\\\\D");
+}
+
+#[test]
+fn test_writer_escape_labels() {
+    SETUP_WRITER!(
+        "+ [Wait; what?] -> next
++ [*Sigh*] -> next
+=== next
+A|B
+-> END",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "Q;Wait\\; what?;00035;\\*Sigh*;00035|P;A\\|B|E;"
+    );
+    assert_eq!(writer.symbols["next"], 35);
+}
+
+#[test]
+fn test_writer_escape_round_trip() {
+    let text = "Wait; what? A|B, C\\D";
+
+    assert_eq!(unescape(&escape(text)), text);
+
+    SETUP_WRITER!(text, reader, writer);
+
+    let records = split_escaped(&writer.output, '|');
+    let fields = split_escaped(records[0], ';');
+
+    assert_eq!(records.len(), 1);
+    assert_eq!(fields, vec!["P", "Wait\\; what? A\\|B, C\\\\D"]);
+    assert_eq!(unescape(fields[1]), text);
+}
+
 // --- COMMENTS ---
 
 #[test]
@@ -436,6 +1139,46 @@ Bonjour monde",
     assert_eq!(reader.lines[2].type_, LineType::Text);
 }
 
+#[test]
+fn test_writer_trailing_comment() {
+    SETUP_WRITER!(
+        "CONST HELLO = \"World\" // The world
+Hello {HELLO} // TODO rewrite
++ [Hello world] -> example // TODO rename
+=== example // First chapter
+Ciao mondo",
+        reader,
+        writer
+    );
+
+    assert_eq!(
+        writer.output,
+        "P;Hello World|Q;Hello world;00034|P;Ciao mondo"
+    );
+
+    assert_eq!(writer.constants["HELLO"], "World");
+    assert_eq!(writer.symbols["example"], 34);
+}
+
+#[test]
+fn test_writer_block_comment_scene() {
+    SETUP_WRITER!(
+        "Hello world
+/*
+=== hello
+Ciao mondo
+-> END
+*/
+Bonjour /* le */ monde",
+        reader,
+        writer
+    );
+
+    assert_eq!(writer.output, "P;Hello world|P;Bonjour monde");
+
+    assert_eq!(writer.symbols.contains_key("hello"), false);
+}
+
 // --- FUNCTIONAL TESTS ---
 
 #[test]